@@ -0,0 +1,54 @@
+use clap::Parser;
+
+const ENVIRONMENT: &str = "\
+Environment variables (see the README for details):
+  DIGITRANSIT_SUBSCRIPTION_KEY  Digitransit API subscription key
+  RSHSL_ROUTER                  Router to plan with: hsl, waltti or finland [default: hsl]
+  RSHSL_ROUTING_URL             Custom OpenTripPlanner GraphQL endpoint
+  RSHSL_GEOCODING_URL           Custom Pelias autocomplete endpoint
+  RSHSL_LANGUAGE                Language of names and alerts: fi, sv or en [default: from locale]
+  RSHSL_CLOCK                   Clock format: 12 or 24 [default: 24]
+  RSHSL_THEME                   Color theme: default or hsl [default: default]
+  RSHSL_NUM_ITINERARIES         Number of routes to show, 3-10 [default: 3]
+  RSHSL_REFRESH_SECS            Seconds between route refreshes, at least 5 [default: 60]
+  RSHSL_TIMEOUT_SECS            Request timeout in seconds [default: 10]
+  RSHSL_MIN_LEG_SECS            Hide route legs shorter than this in the overview [default: 60]
+  RSHSL_WALK_SPEED              Walking speed in m/s, 0.5-3.0 [default: router default]
+  RSHSL_MAX_WALK_DISTANCE       Maximum walk distance in meters, 250-5000 [default: none]
+  RSHSL_MAX_TRANSFERS           Maximum number of changes [default: none]
+  RSHSL_MIN_TRANSFER_SECS       Minimum time for changes in seconds, 0-1800 [default: router default]
+  RSHSL_OPTIMIZE                Preferred routes: fastest, transfers or walking [default: fastest]
+  RSHSL_FOCUS_POINT             Search focus as lat,lon or none [default: central Helsinki with hsl]
+  RSHSL_CURRENT_LOCATION        Current location as lat,lon [default: none]
+  RSHSL_IP_LOCATION             Look up the current location from the IP address: true or false [default: false]
+  RSHSL_LOCATION_LIMIT          Number of location search results [default: 8]
+  RSHSL_SEARCH                  Search locations while typing or on Enter: typing or enter [default: typing]
+  RSHSL_LOG                     Log level or filter directives [default: warn]
+  RSHSL_LOG_DIR                 Log directory [default: rshsl in the user cache directory]
+  NO_COLOR                      Draw without colors when set";
+
+#[derive(Parser)]
+#[command(version, about, after_help = ENVIRONMENT)]
+pub struct Args {
+    /// Print the best itinerary from this location and exit, requires --to
+    #[arg(long, requires = "to")]
+    from: Option<String>,
+    /// Print the best itinerary to this location and exit, requires --from
+    #[arg(long, requires = "from")]
+    to: Option<String>,
+    /// Draw the interface below the prompt instead of the alternate screen
+    #[arg(long)]
+    pub inline: bool,
+    /// Draw without colors, also enabled by setting NO_COLOR
+    #[arg(long)]
+    pub no_color: bool,
+    /// Log level or filter directives such as "debug" or "rshsl=trace"
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+}
+
+impl Args {
+    pub fn trip(&self) -> Option<(&str, &str)> {
+        self.from.as_deref().zip(self.to.as_deref())
+    }
+}
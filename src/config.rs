@@ -0,0 +1,395 @@
+use std::{env, fs, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::RequestBuilder;
+
+use crate::{keymap::Keymap, theme::Theme};
+
+const DEFAULT_REFRESH_SECS: u64 = 60;
+const MIN_REFRESH_SECS: u64 = 5;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+pub const NUM_ITINERARIES_RANGE: (i64, i64) = (3, 10);
+// Legs shorter than this are hidden from the compact itinerary bar
+const DEFAULT_MIN_LEG_SECS: f64 = 60.0;
+const WALK_SPEED_RANGE: (f64, f64) = (0.5, 3.0);
+const DEFAULT_LOCATION_LIMIT: usize = 8;
+// Central railway station, used to bias autocomplete towards Helsinki
+const DEFAULT_FOCUS_POINT: (f64, f64) = (60.1710, 24.9414);
+pub const MAX_WALK_DISTANCE_RANGE: (f64, f64) = (250.0, 5000.0);
+pub const MIN_TRANSFER_TIME_RANGE: (i64, i64) = (0, 1800);
+const DEFAULT_GEOCODING_URL: &str = "https://api.digitransit.fi/geocoding/v1/autocomplete";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Language {
+    Finnish,
+    Swedish,
+    English,
+}
+
+impl Language {
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::Finnish => "fi",
+            Language::Swedish => "sv",
+            Language::English => "en",
+        }
+    }
+
+    pub fn next(self) -> Language {
+        match self {
+            Language::Finnish => Language::Swedish,
+            Language::Swedish => Language::English,
+            Language::English => Language::Finnish,
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Language> {
+        match value.get(..2).map(|code| code.to_ascii_lowercase()).as_deref() {
+            Some("fi") => Ok(Language::Finnish),
+            Some("sv") => Ok(Language::Swedish),
+            Some("en") => Ok(Language::English),
+            _ => Err(anyhow!("Unsupported language {:?}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Router {
+    Hsl,
+    Waltti,
+    Finland,
+}
+
+impl Router {
+    fn name(self) -> &'static str {
+        match self {
+            Router::Hsl => "hsl",
+            Router::Waltti => "waltti",
+            Router::Finland => "finland",
+        }
+    }
+
+    fn url(self) -> String {
+        format!("https://api.digitransit.fi/routing/v1/routers/{}/index/graphql", self.name())
+    }
+}
+
+impl FromStr for Router {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Router> {
+        match value.to_ascii_lowercase().as_str() {
+            "hsl" => Ok(Router::Hsl),
+            "waltti" => Ok(Router::Waltti),
+            "finland" => Ok(Router::Finland),
+            _ => Err(anyhow!("Unsupported router {:?}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    TwentyFourHour,
+    TwelveHour,
+}
+
+impl Clock {
+    pub fn format(self) -> &'static str {
+        match self {
+            Clock::TwentyFourHour => "%H:%M",
+            Clock::TwelveHour => "%-I:%M %p",
+        }
+    }
+}
+
+impl FromStr for Clock {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Clock> {
+        match value.trim_end_matches(['h', 'H']) {
+            "24" => Ok(Clock::TwentyFourHour),
+            "12" => Ok(Clock::TwelveHour),
+            _ => Err(anyhow!("Unsupported clock {:?}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    Typing,
+    Enter,
+}
+
+impl FromStr for SearchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<SearchMode> {
+        match value.to_lowercase().as_str() {
+            "typing" => Ok(SearchMode::Typing),
+            "enter" => Ok(SearchMode::Enter),
+            _ => Err(anyhow!("Unsupported search mode {:?}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preference {
+    Fastest,
+    FewestTransfers,
+    LeastWalking,
+}
+
+impl Preference {
+    pub fn name(self) -> &'static str {
+        match self {
+            Preference::Fastest => "Fastest",
+            Preference::FewestTransfers => "Fewest changes",
+            Preference::LeastWalking => "Least walking",
+        }
+    }
+
+    pub fn next(self) -> Preference {
+        match self {
+            Preference::Fastest => Preference::FewestTransfers,
+            Preference::FewestTransfers => Preference::LeastWalking,
+            Preference::LeastWalking => Preference::Fastest,
+        }
+    }
+}
+
+impl FromStr for Preference {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Preference> {
+        match value.to_ascii_lowercase().as_str() {
+            "fastest" => Ok(Preference::Fastest),
+            "transfers" => Ok(Preference::FewestTransfers),
+            "walking" => Ok(Preference::LeastWalking),
+            _ => Err(anyhow!("Unsupported preference {:?}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub api_key: Option<String>,
+    pub routing_url: String,
+    pub geocoding_url: String,
+    pub refresh_interval: Duration,
+    pub request_timeout: Duration,
+    pub walk_speed: Option<f64>,
+    pub max_walk_distance: Option<f64>,
+    pub max_transfers: Option<i64>,
+    pub min_transfer_time: Option<i64>,
+    pub preference: Preference,
+    pub clock: Clock,
+    pub num_itineraries: i64,
+    pub language: Language,
+    pub min_leg_duration: f64,
+    pub current_location: Option<(f64, f64)>,
+    pub ip_location: bool,
+    pub focus_point: Option<(f64, f64)>,
+    pub location_limit: usize,
+    pub search: SearchMode,
+    pub theme: Theme,
+    pub keymap: Keymap,
+}
+
+fn env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tracing::warn!("Ignoring invalid value {:?} for {}", value, name);
+            None
+        }
+    }
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("rshsl"))
+}
+
+pub fn log_dir() -> PathBuf {
+    env::var_os("RSHSL_LOG_DIR")
+        .map(PathBuf::from)
+        .or_else(|| dirs::cache_dir().map(|dir| dir.join("rshsl")))
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+fn validate_api_key(key: &str) -> Result<String> {
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("Digitransit subscription key is empty; see README");
+    }
+    if key.chars().any(char::is_whitespace) {
+        bail!("Digitransit subscription key contains whitespace; see README");
+    }
+    Ok(key.to_string())
+}
+
+fn api_key() -> Result<String> {
+    if let Ok(key) = env::var("DIGITRANSIT_SUBSCRIPTION_KEY") {
+        return validate_api_key(&key);
+    }
+    let path = config_dir()
+        .map(|dir| dir.join("apikey"))
+        .ok_or_else(|| anyhow!("Could not determine the configuration directory"))?;
+    match fs::read_to_string(&path) {
+        Ok(key) => validate_api_key(&key),
+        Err(error) => Err(anyhow!(
+            "Digitransit subscription key not found: set DIGITRANSIT_SUBSCRIPTION_KEY or write \
+             it to {} ({})",
+            path.display(),
+            error
+        )),
+    }
+}
+
+fn walk_speed() -> Option<f64> {
+    let walk_speed: f64 = env_var("RSHSL_WALK_SPEED")?;
+    let (min, max) = WALK_SPEED_RANGE;
+    if (min..=max).contains(&walk_speed) {
+        Some(walk_speed)
+    } else {
+        tracing::warn!("Ignoring walk speed {} m/s outside of {}-{} m/s", walk_speed, min, max);
+        None
+    }
+}
+
+fn min_transfer_time() -> Option<i64> {
+    let min_transfer_time: i64 = env_var("RSHSL_MIN_TRANSFER_SECS")?;
+    let (min, max) = MIN_TRANSFER_TIME_RANGE;
+    if (min..=max).contains(&min_transfer_time) {
+        Some(min_transfer_time)
+    } else {
+        tracing::warn!(
+            "Ignoring minimum transfer time {} s outside of {}-{} s",
+            min_transfer_time,
+            min,
+            max
+        );
+        None
+    }
+}
+
+fn coordinates_var(name: &str) -> Option<(f64, f64)> {
+    let value = env::var(name).ok()?;
+    let coordinates = value
+        .split_once(',')
+        .and_then(|(lat, lon)| Some((lat.trim().parse().ok()?, lon.trim().parse().ok()?)));
+    if coordinates.is_none() {
+        tracing::warn!("Ignoring invalid value {:?} for {}", value, name);
+    }
+    coordinates
+}
+
+fn focus_point(router: Router) -> Option<(f64, f64)> {
+    let default = Some(DEFAULT_FOCUS_POINT).filter(|_| router == Router::Hsl);
+    match env::var("RSHSL_FOCUS_POINT").as_deref() {
+        Ok("none") => None,
+        Ok(_) => coordinates_var("RSHSL_FOCUS_POINT").or(default),
+        Err(_) => default,
+    }
+}
+
+fn language() -> Language {
+    if let Some(language) = env_var("RSHSL_LANGUAGE") {
+        return language;
+    }
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find_map(|locale| locale.parse().ok())
+        .unwrap_or(Language::Finnish)
+}
+
+impl Config {
+    pub fn from_env() -> Result<Config> {
+        let refresh_secs = env_var("RSHSL_REFRESH_SECS").unwrap_or(DEFAULT_REFRESH_SECS);
+        let router = env_var("RSHSL_ROUTER").unwrap_or(Router::Hsl);
+        let routing_url = env::var("RSHSL_ROUTING_URL").ok();
+        let geocoding_url = env::var("RSHSL_GEOCODING_URL").ok();
+        // Self-hosted endpoints usually don't need a Digitransit subscription key
+        let api_key = match api_key() {
+            Ok(key) => Some(key),
+            Err(error) if routing_url.is_some() || geocoding_url.is_some() => {
+                tracing::info!("Using custom endpoints without a subscription key: {}", error);
+                None
+            }
+            Err(error) => return Err(error),
+        };
+        Ok(Config {
+            api_key,
+            routing_url: routing_url.unwrap_or_else(|| router.url()),
+            geocoding_url: geocoding_url.unwrap_or_else(|| DEFAULT_GEOCODING_URL.to_string()),
+            refresh_interval: Duration::from_secs(refresh_secs.max(MIN_REFRESH_SECS)),
+            request_timeout: Duration::from_secs(
+                env_var("RSHSL_TIMEOUT_SECS").unwrap_or(DEFAULT_TIMEOUT_SECS).max(1),
+            ),
+            walk_speed: walk_speed(),
+            max_walk_distance: env_var("RSHSL_MAX_WALK_DISTANCE").map(|distance: f64| {
+                distance.clamp(MAX_WALK_DISTANCE_RANGE.0, MAX_WALK_DISTANCE_RANGE.1)
+            }),
+            max_transfers: env_var("RSHSL_MAX_TRANSFERS").map(|transfers: i64| transfers.max(0)),
+            min_transfer_time: min_transfer_time(),
+            preference: env_var("RSHSL_OPTIMIZE").unwrap_or(Preference::Fastest),
+            clock: env_var("RSHSL_CLOCK").unwrap_or(Clock::TwentyFourHour),
+            num_itineraries: env_var("RSHSL_NUM_ITINERARIES")
+                .unwrap_or(NUM_ITINERARIES_RANGE.0)
+                .clamp(NUM_ITINERARIES_RANGE.0, NUM_ITINERARIES_RANGE.1),
+            language: language(),
+            min_leg_duration: env_var("RSHSL_MIN_LEG_SECS")
+                .unwrap_or(DEFAULT_MIN_LEG_SECS)
+                .max(0.0),
+            current_location: coordinates_var("RSHSL_CURRENT_LOCATION"),
+            ip_location: env_var("RSHSL_IP_LOCATION").unwrap_or(false),
+            focus_point: focus_point(router),
+            location_limit: env_var("RSHSL_LOCATION_LIMIT")
+                .unwrap_or(DEFAULT_LOCATION_LIMIT)
+                .max(1),
+            search: env_var("RSHSL_SEARCH").unwrap_or(SearchMode::Typing),
+            theme: Theme::load(),
+            keymap: Keymap::load(),
+        })
+    }
+    pub fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(api_key) => request.header("digitransit-subscription-key", api_key),
+            None => request,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    pub fn with_endpoints(routing_url: String, geocoding_url: String) -> Config {
+        Config {
+            api_key: None,
+            routing_url,
+            geocoding_url,
+            refresh_interval: Duration::from_secs(DEFAULT_REFRESH_SECS),
+            request_timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            walk_speed: None,
+            max_walk_distance: None,
+            max_transfers: None,
+            min_transfer_time: None,
+            preference: Preference::Fastest,
+            clock: Clock::TwentyFourHour,
+            num_itineraries: NUM_ITINERARIES_RANGE.0,
+            language: Language::Finnish,
+            min_leg_duration: DEFAULT_MIN_LEG_SECS,
+            current_location: None,
+            ip_location: false,
+            focus_point: None,
+            location_limit: DEFAULT_LOCATION_LIMIT,
+            search: SearchMode::Typing,
+            theme: Theme::default(),
+            keymap: Keymap::default(),
+        }
+    }
+}
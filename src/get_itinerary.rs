@@ -1,278 +1,2229 @@
 use std::{
-    sync::{atomic::AtomicBool, Arc},
-    time::Duration,
+    collections::{BTreeSet, HashMap},
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
-use chrono::{Local, TimeZone};
-use crossterm::event::{self, Event, KeyCode};
-use graphql_client::{GraphQLQuery, Response};
+use anyhow::{anyhow, Result};
+use arboard::Clipboard;
+use chrono::{DateTime, Duration as ChronoDuration, Local, TimeZone};
+use crossterm::event::{self, Event, MouseButton, MouseEventKind};
+use graphql_client::{GraphQLQuery, QueryBody, Response};
 use ratatui::{
     backend::Backend,
-    layout::{Alignment, Constraint, Direction, Layout},
+    layout::{Alignment, Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
+    symbols::Marker,
     text::{Line, Span},
-    widgets::{Block, Borders, Paragraph},
-    Terminal,
+    widgets::{
+        block::{Position, Title},
+        canvas::{Canvas, Line as CanvasLine},
+        Block, BorderType, Borders, Paragraph, Wrap,
+    },
+    Frame, Terminal,
 };
 use reqwest::Client;
 use std::sync::atomic::Ordering::Relaxed;
-use tokio::sync::RwLock;
-use tracing::info;
+use tokio::sync::{Notify, RwLock};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 
-use crate::get_location::Feature;
+use crate::{
+    config::{
+        Clock, Config, Language, Preference, MAX_WALK_DISTANCE_RANGE, MIN_TRANSFER_TIME_RANGE,
+        NUM_ITINERARIES_RANGE,
+    },
+    get_location::Feature,
+    get_time::TripTime,
+    gpx, help,
+    http::{is_rate_limited, send_timed},
+    keymap::{ItineraryAction, Key},
+    persistence::{self, Trip},
+    theme::Theme,
+    ui::{self, format_distance},
+};
 
 use self::plan_query::{
-    InputCoordinates, Mode, PlanQueryPlanItineraries, PlanQueryPlanItinerariesLegs,
+    InputCoordinates, Mode, OptimizeType, PlanQueryPlanItineraries, PlanQueryPlanItinerariesLegs,
+    PlanQueryPlanItinerariesLegsRouteAlerts, PlanQueryPlanItinerariesLegsSteps, Qualifier,
+    RelativeDirection, TransportMode,
 };
 
 type Long = u64;
+type Polyline = String;
 
 #[derive(GraphQLQuery)]
 #[graphql(
     schema_path = "src/schema.graphql",
     query_path = "src/queries/plan.graphql",
-    response_derives = "Debug,PartialEq"
+    response_derives = "Debug,Clone,PartialEq,Serialize"
 )]
 pub struct PlanQuery;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/schema.graphql",
+    query_path = "src/queries/departures.graphql",
+    response_derives = "Debug"
+)]
+struct DeparturesQuery;
+
+// Later departures of the first transit leg, keyed by itinerary
+type Departures = HashMap<(Option<u64>, Option<String>), Vec<u64>>;
+
 fn format_duration(duration: &Duration) -> String {
     let seconds = duration.as_secs();
     let hours = seconds / 3600;
     let minutes = seconds % 3600 / 60;
     let seconds = seconds % 3600 % 60;
-    [
+    let formatted = [
         if hours > 0 { format!("{}h ", hours) } else { String::new() },
         if minutes > 0 { format!("{}m ", minutes) } else { String::new() },
         if seconds > 0 { format!("{}s ", seconds) } else { String::new() },
     ]
     .join("")
     .trim()
-    .to_string()
+    .to_string();
+    if formatted.is_empty() {
+        "0s".to_string()
+    } else {
+        formatted
+    }
+}
+
+fn local_time(timestamp: Long) -> Option<DateTime<Local>> {
+    Local.timestamp_opt(timestamp as i64 / 1000, 0).single()
+}
+
+fn format_time(timestamp: Option<Long>, clock: Clock) -> String {
+    timestamp
+        .and_then(local_time)
+        .map_or_else(|| "??:??".to_string(), |time| time.format(clock.format()).to_string())
 }
 
-fn format_title(itinerary: &PlanQueryPlanItineraries) -> String {
+fn format_title(itinerary: &PlanQueryPlanItineraries, clock: Clock) -> String {
     format!(
         "[ {} - {} | {} ]",
-        Local
-            .timestamp_opt(itinerary.start_time.unwrap() as i64 / 1000, 0)
-            .single()
-            .unwrap()
-            .format("%H:%M"),
-        Local
-            .timestamp_opt(itinerary.end_time.unwrap() as i64 / 1000, 0)
-            .single()
-            .unwrap()
-            .format("%H:%M"),
-        format_duration(&Duration::from_secs(itinerary.duration.unwrap()))
+        format_time(itinerary.start_time, clock),
+        format_time(itinerary.end_time, clock),
+        itinerary.duration.map_or_else(
+            || "??".to_string(),
+            |duration| format_duration(&Duration::from_secs(duration))
+        )
     )
 }
 
-pub async fn get_itinerary<B: Backend>(
-    terminal: &mut Terminal<B>,
+fn format_fare(itinerary: &PlanQueryPlanItineraries) -> Option<String> {
+    // HSL zones are consecutive letters, so a trip from A to C also crosses B
+    let zones: BTreeSet<char> = itinerary
+        .legs
+        .iter()
+        .flatten()
+        .filter(|leg| is_transit(&leg_mode(leg)))
+        .flat_map(|leg| {
+            [
+                leg.from.stop.as_ref().and_then(|stop| stop.zone_id.clone()),
+                leg.to.stop.as_ref().and_then(|stop| stop.zone_id.clone()),
+            ]
+        })
+        .flatten()
+        .filter_map(|zone| match zone.chars().collect::<Vec<_>>()[..] {
+            [zone] if zone.is_ascii_alphabetic() => Some(zone),
+            _ => None,
+        })
+        .collect();
+    let zones: Option<String> = zones.first().zip(zones.last()).map(|(&a, &b)| (a..=b).collect());
+    let fare = itinerary.fares.iter().flatten().flatten().find(|fare| fare.cents.unwrap_or(0) > 0);
+    let ticket = fare
+        .and_then(|fare| fare.components.as_ref())
+        .map(|components| {
+            components
+                .iter()
+                .flatten()
+                .filter_map(|component| component.fare_id.as_deref())
+                .map(|fare_id| fare_id.rsplit(':').next().unwrap_or(fare_id))
+                .collect::<Vec<_>>()
+                .join(" + ")
+        })
+        .filter(|ticket| !ticket.is_empty())
+        .or(zones)?;
+    Some(match fare.and_then(|fare| Some((fare.cents?, fare.currency.as_deref()))) {
+        Some((cents, Some("EUR") | None)) => {
+            format!("\u{1F3AB} {} {:.2} \u{20AC}", ticket, cents as f64 / 100.0)
+        }
+        Some((cents, Some(currency))) => {
+            format!("\u{1F3AB} {} {:.2} {}", ticket, cents as f64 / 100.0, currency)
+        }
+        None => format!("\u{1F3AB} {}", ticket),
+    })
+}
+
+fn itinerary_key(itinerary: &PlanQueryPlanItineraries) -> (Option<u64>, Option<String>) {
+    let route = itinerary
+        .legs
+        .iter()
+        .flatten()
+        .find_map(|leg| leg.route.as_ref())
+        .map(|route| route.gtfs_id.clone());
+    (itinerary.start_time, route)
+}
+
+// Refreshes can reorder the routes, so follow the selected trip rather than its index
+fn reselect(
+    itineraries: &[Option<PlanQueryPlanItineraries>],
+    key: &(Option<u64>, Option<String>),
+) -> Option<usize> {
+    itineraries
+        .iter()
+        .position(|itinerary| itinerary.as_ref().map(itinerary_key).as_ref() == Some(key))
+}
+
+// Ties go to the earlier route in the list
+fn best_by<K: Ord>(
+    itineraries: &[Option<PlanQueryPlanItineraries>],
+    key: impl Fn(&PlanQueryPlanItineraries) -> Option<K>,
+) -> Option<usize> {
+    itineraries
+        .iter()
+        .enumerate()
+        .filter_map(|(index, itinerary)| Some((index, key(itinerary.as_ref()?)?)))
+        .min_by(|(_, a), (_, b)| a.cmp(b))
+        .map(|(index, _)| index)
+}
+
+fn badges(itineraries: &[Option<PlanQueryPlanItineraries>]) -> Vec<String> {
+    let mut badges = vec![String::new(); itineraries.len()];
+    if itineraries.iter().flatten().count() < 2 {
+        return badges;
+    }
+    if let Some(index) = best_by(itineraries, |itinerary| itinerary.duration) {
+        badges[index].push_str("\u{26A1} fastest");
+    }
+    if let Some(index) = best_by(itineraries, |itinerary| itinerary.end_time) {
+        if !badges[index].is_empty() {
+            badges[index].push(' ');
+        }
+        badges[index].push_str("\u{1F3C1} earliest");
+    }
+    badges
+}
+
+fn transfers(itinerary: &PlanQueryPlanItineraries) -> usize {
+    itinerary
+        .legs
+        .iter()
+        .flatten()
+        .filter(|leg| is_transit(&leg_mode(leg)))
+        .count()
+        .saturating_sub(1)
+}
+
+fn format_summary(itinerary: &PlanQueryPlanItineraries) -> String {
+    let walk_distance: f64 = itinerary
+        .legs
+        .iter()
+        .flatten()
+        .filter(|leg| leg.mode == Some(Mode::WALK))
+        .filter_map(|leg| leg.distance)
+        .sum();
+    let transfers = transfers(itinerary);
+    let mut summary = format!(
+        "\u{1F6B6} {} \u{00B7} {}",
+        format_distance(walk_distance),
+        match transfers {
+            0 => "no changes".to_string(),
+            1 => "1 change".to_string(),
+            transfers => format!("{} changes", transfers),
+        }
+    );
+    if let Some(fare) = format_fare(itinerary) {
+        summary = format!("{} \u{00B7} {}", summary, fare);
+    }
+    if let Some(co2) = itinerary.emissions_per_person.as_ref().and_then(|emissions| emissions.co2) {
+        summary = format!("{} \u{00B7} \u{2248} {:.0} g CO\u{2082}", summary, co2);
+    }
+    format!("[ {} ]", summary)
+}
+
+fn mode_icon(mode: &Mode) -> String {
+    match mode {
+        Mode::WALK => "\u{1F6B6}".to_string(),
+        Mode::BICYCLE => "\u{1F6B2}".to_string(),
+        Mode::BUS => "\u{1F68C}".to_string(),
+        Mode::TRAM => "\u{1F68A}".to_string(),
+        Mode::RAIL => "\u{1F686}".to_string(),
+        Mode::SUBWAY => "\u{1F687}".to_string(),
+        Mode::FERRY => "\u{26F4}".to_string(),
+        Mode::Other(other) => other.clone(),
+        _ => format!("{:?}", mode),
+    }
+}
+
+fn mode_color(theme: &Theme, mode: &Mode) -> Color {
+    match mode {
+        Mode::WALK => theme.walk,
+        Mode::BICYCLE => theme.bicycle,
+        Mode::BUS => theme.bus,
+        Mode::TRAM => theme.tram,
+        Mode::RAIL => theme.rail,
+        Mode::SUBWAY => theme.subway,
+        Mode::FERRY => theme.ferry,
+        _ => theme.other,
+    }
+}
+
+const HELP: &[(&[ItineraryAction], &str)] = &[
+    (&[ItineraryAction::Up, ItineraryAction::Down], "Select route"),
+    (&[ItineraryAction::First, ItineraryAction::Last], "Select the first or last route"),
+    (&[ItineraryAction::PageUp, ItineraryAction::PageDown], "Select route by page"),
+    (&[ItineraryAction::Details], "Show route details"),
+    (&[ItineraryAction::Close], "Close route details"),
+    (&[ItineraryAction::Refresh], "Refresh routes"),
+    (&[ItineraryAction::Swap], "Swap origin and destination"),
+    (&[ItineraryAction::Later, ItineraryAction::Earlier], "Show later or earlier routes"),
+    (&[ItineraryAction::Back], "Pick new locations"),
+    (&[ItineraryAction::Stops], "Toggle intermediate stops"),
+    (&[ItineraryAction::Compact], "Toggle the compact one-line view"),
+    (&[ItineraryAction::Language], "Change language"),
+    (&[ItineraryAction::More, ItineraryAction::Fewer], "Show more or fewer routes"),
+    (&[ItineraryAction::Wheelchair], "Toggle wheelchair accessible routes"),
+    (
+        &[ItineraryAction::LessWalking, ItineraryAction::MoreWalking],
+        "Decrease or increase the maximum walk distance",
+    ),
+    (&[ItineraryAction::MaxTransfers], "Cycle the maximum number of changes"),
+    (&[ItineraryAction::Preference], "Prefer the fastest routes, fewest changes or least walking"),
+    (
+        &[ItineraryAction::ShorterTransfers, ItineraryAction::LongerTransfers],
+        "Decrease or increase the minimum time for changes",
+    ),
+    (&[ItineraryAction::CityBikes], "Toggle city bikes"),
+    (
+        &[
+            ItineraryAction::ToggleMode(0),
+            ItineraryAction::ToggleMode(1),
+            ItineraryAction::ToggleMode(2),
+            ItineraryAction::ToggleMode(3),
+            ItineraryAction::ToggleMode(4),
+        ],
+        "Toggle bus, tram, train, metro or ferry",
+    ),
+    (&[ItineraryAction::ExportJson], "Export the selected route as JSON"),
+    (&[ItineraryAction::Copy], "Copy a summary of the selected route"),
+    (&[ItineraryAction::ExportGpx], "Export the selected route as GPX (in route details)"),
+    (&[ItineraryAction::Map], "Toggle the map (in route details)"),
+    (&[ItineraryAction::Help], "Show this help"),
+    (&[ItineraryAction::Quit], "Quit"),
+];
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(32);
+const MAX_RATE_LIMITED_BACKOFF: Duration = Duration::from_secs(600);
+const WALK_DISTANCE_STEP: f64 = 250.0;
+const DEFAULT_MAX_WALK_DISTANCE: f64 = 1000.0;
+const MAX_TRANSFERS_LIMIT: i64 = 3;
+const TRANSFER_TIME_STEP: i64 = 60;
+const PAGE_OFFSET_MINUTES: i64 = 1;
+// Roughly how many seconds of travel time one change is worth
+const FEWEST_TRANSFERS_PENALTY: i64 = 600;
+// The router default is 2.0
+const LEAST_WALKING_RELUCTANCE: f64 = 5.0;
+const COUNTDOWN_WARNING_SECS: i64 = 120;
+const NOTICE_DURATION: Duration = Duration::from_secs(5);
+const ITINERARY_HEIGHT: u16 = 5;
+
+fn leg_mode(leg: &PlanQueryPlanItinerariesLegs) -> Mode {
+    leg.mode.clone().unwrap_or_else(|| Mode::Other("?".to_string()))
+}
+
+fn is_transit(mode: &Mode) -> bool {
+    !matches!(mode, Mode::WALK | Mode::BICYCLE)
+}
+
+fn is_rented_bike(leg: &PlanQueryPlanItinerariesLegs) -> bool {
+    leg.mode == Some(Mode::BICYCLE) && leg.rented_bike.unwrap_or(false)
+}
+
+#[derive(Clone)]
+struct PlanParameters {
     from: Feature,
+    via: Vec<Feature>,
     to: Feature,
-) -> Result<()> {
-    let form_coordinates = InputCoordinates {
-        lat: from.geometry.coordinates[1],
-        lon: from.geometry.coordinates[0],
-        address: Some(from.properties.label.clone()),
-        location_slack: None,
+    trip_time: TripTime,
+    walk_speed: Option<f64>,
+    max_walk_distance: Option<f64>,
+    max_transfers: Option<i64>,
+    min_transfer_time: Option<i64>,
+    preference: Preference,
+    wheelchair: bool,
+    num_itineraries: i64,
+    language: Language,
+    bike_rental: bool,
+    enabled_modes: [bool; TRANSIT_MODES.len()],
+}
+
+const TRANSIT_MODES: [Mode; 5] = [Mode::BUS, Mode::TRAM, Mode::RAIL, Mode::SUBWAY, Mode::FERRY];
+
+fn coordinates(feature: &Feature) -> Result<InputCoordinates> {
+    match feature.geometry.coordinates[..] {
+        [lon, lat, ..] => Ok(InputCoordinates {
+            lat,
+            lon,
+            address: Some(feature.properties.label.clone()),
+            location_slack: None,
+        }),
+        _ => Err(anyhow!("Location {:?} has no coordinates", feature.properties.label)),
+    }
+}
+
+impl PlanParameters {
+    fn new(
+        config: &Config,
+        from: Feature,
+        via: Vec<Feature>,
+        to: Feature,
+        trip_time: TripTime,
+    ) -> PlanParameters {
+        PlanParameters {
+            from,
+            via,
+            to,
+            trip_time,
+            walk_speed: config.walk_speed,
+            max_walk_distance: config.max_walk_distance,
+            max_transfers: config.max_transfers,
+            min_transfer_time: config.min_transfer_time,
+            preference: config.preference,
+            wheelchair: false,
+            num_itineraries: config.num_itineraries,
+            language: config.language,
+            bike_rental: false,
+            enabled_modes: [true; TRANSIT_MODES.len()],
+        }
+    }
+
+    fn route_name(&self) -> String {
+        persistence::route_name(&self.from, &self.via, &self.to)
+    }
+
+    fn intermediate_places(&self) -> Result<Option<Vec<Option<InputCoordinates>>>> {
+        if self.via.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.via.iter().map(|feature| coordinates(feature).map(Some)).collect::<Result<_>>()?,
+        ))
+    }
+
+    fn transport_modes(&self) -> Option<Vec<Option<TransportMode>>> {
+        if !self.bike_rental && self.enabled_modes.iter().all(|&enabled| enabled) {
+            return None;
+        }
+        let mode = |mode, qualifier| Some(TransportMode { mode, qualifier });
+        let mut modes = vec![mode(Mode::WALK, None)];
+        if self.bike_rental {
+            modes.push(mode(Mode::BICYCLE, Some(Qualifier::RENT)));
+        }
+        modes.extend(
+            TRANSIT_MODES
+                .into_iter()
+                .zip(self.enabled_modes)
+                .filter(|(_, enabled)| *enabled)
+                .map(|(transit, _)| mode(transit, None)),
+        );
+        Some(modes)
+    }
+
+    fn variables(&self) -> Result<plan_query::Variables> {
+        Ok(plan_query::Variables {
+            from: coordinates(&self.from)?,
+            to: coordinates(&self.to)?,
+            date: Some(self.trip_time.time.format("%Y-%m-%d").to_string()),
+            time: Some(self.trip_time.time.format("%H:%M:%S").to_string()),
+            arrive_by: Some(self.trip_time.arrive_by),
+            walk_speed: self.walk_speed,
+            wheelchair: Some(self.wheelchair),
+            num_itineraries: Some(self.num_itineraries),
+            locale: Some(self.language.code().to_string()),
+            allow_bike_rental: Some(self.bike_rental),
+            transport_modes: self.transport_modes(),
+            max_walk_distance: self.max_walk_distance,
+            intermediate_places: self.intermediate_places()?,
+            max_transfers: self.max_transfers,
+            min_transfer_time: self.min_transfer_time,
+            optimize: Some(match self.preference {
+                Preference::FewestTransfers => OptimizeType::TRANSFERS,
+                // Walking is only penalised through walkReluctance, which QUICK keeps in effect
+                Preference::Fastest | Preference::LeastWalking => OptimizeType::QUICK,
+            }),
+            transfer_penalty: match self.preference {
+                Preference::FewestTransfers => Some(FEWEST_TRANSFERS_PENALTY),
+                _ => None,
+            },
+            walk_reluctance: match self.preference {
+                Preference::LeastWalking => Some(LEAST_WALKING_RELUCTANCE),
+                _ => None,
+            },
+        })
+    }
+}
+
+// Pages forward from the latest departure or backward from the earliest arrival
+fn paged_trip_time(
+    itineraries: &[Option<PlanQueryPlanItineraries>],
+    later: bool,
+) -> Option<TripTime> {
+    let itineraries = itineraries.iter().flatten();
+    if later {
+        let departure = itineraries.filter_map(|itinerary| itinerary.start_time).max()?;
+        Some(TripTime {
+            time: local_time(departure)?.naive_local()
+                + ChronoDuration::minutes(PAGE_OFFSET_MINUTES),
+            arrive_by: false,
+        })
+    } else {
+        let arrival = itineraries.filter_map(|itinerary| itinerary.end_time).min()?;
+        Some(TripTime {
+            time: local_time(arrival)?.naive_local() - ChronoDuration::minutes(PAGE_OFFSET_MINUTES),
+            arrive_by: true,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct RouterError(String);
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Router error: {}", self.0)
+    }
+}
+
+impl std::error::Error for RouterError {}
+
+async fn fetch_itineraries(
+    client: &Client,
+    config: &Config,
+    body: &QueryBody<plan_query::Variables>,
+) -> Result<Vec<Option<PlanQueryPlanItineraries>>> {
+    let request = config.authorize(client.post(&config.routing_url)).json(body);
+    let response: Response<plan_query::ResponseData> =
+        send_timed(request, "plan").await?.error_for_status()?.json().await?;
+    let errors = response.errors.unwrap_or_default();
+    for error in &errors {
+        warn!("Router returned an error: {}", error);
+    }
+    match response.data.and_then(|data| data.plan) {
+        Some(plan) => Ok(plan.itineraries),
+        None if !errors.is_empty() => {
+            let messages: Vec<_> = errors.iter().map(|error| error.message.as_str()).collect();
+            Err(RouterError(messages.join("; ")).into())
+        }
+        None => Err(anyhow!("Plan missing from response")),
+    }
+}
+
+pub async fn plan(
+    client: &Client,
+    config: &Config,
+    from: Feature,
+    via: Vec<Feature>,
+    to: Feature,
+    trip_time: TripTime,
+) -> Result<Vec<PlanQueryPlanItineraries>> {
+    let parameters = PlanParameters::new(config, from, via, to, trip_time);
+    let body = PlanQuery::build_query(parameters.variables()?);
+    Ok(fetch_itineraries(client, config, &body).await?.into_iter().flatten().collect())
+}
+
+pub fn format_plain(itinerary: &PlanQueryPlanItineraries, clock: Clock) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{} - {} | {}",
+        format_time(itinerary.start_time, clock),
+        format_time(itinerary.end_time, clock),
+        itinerary.duration.map_or_else(
+            || "??".to_string(),
+            |duration| format_duration(&Duration::from_secs(duration))
+        )
+    )];
+    for leg in itinerary.legs.iter().flatten() {
+        let mode = leg.mode.as_ref().map_or_else(|| "?".to_string(), |mode| format!("{:?}", mode));
+        let route = leg.route.as_ref().and_then(|route| route.short_name.clone());
+        let place = |stop: Option<String>, name: &Option<String>| {
+            stop.or_else(|| name.clone()).unwrap_or_default()
+        };
+        lines.push(format!(
+            "{}  {} {}  {} -> {}",
+            format_time(leg.start_time, clock),
+            mode,
+            route.unwrap_or_else(|| format_distance(leg.distance.unwrap_or(0.0))),
+            place(leg.from.stop.as_ref().map(|stop| stop.name.clone()), &leg.from.name),
+            place(leg.to.stop.as_ref().map(|stop| stop.name.clone()), &leg.to.name),
+        ));
+    }
+    lines
+}
+
+fn delay_spans(leg: &PlanQueryPlanItinerariesLegs) -> Vec<Span<'static>> {
+    if !is_transit(&leg_mode(leg)) || !leg.real_time.unwrap_or(false) {
+        return vec![];
+    }
+    let minutes = leg.departure_delay.unwrap_or(0) / 60;
+    let (text, color) = match minutes {
+        0 => ("on time".to_string(), Color::Green),
+        minutes if minutes < 0 => (format!("{}m early", -minutes), Color::Green),
+        minutes if minutes < 3 => (format!("+{}m late", minutes), Color::Yellow),
+        minutes => (format!("+{}m late", minutes), Color::Red),
+    };
+    vec![
+        Span::raw(" "),
+        Span::styled(text, Style::default().fg(color).add_modifier(Modifier::BOLD)),
+    ]
+}
+
+fn step_instruction(step: &PlanQueryPlanItinerariesLegsSteps) -> String {
+    let (icon, action) = match step.relative_direction {
+        Some(RelativeDirection::DEPART) => ("\u{2191}", "Head"),
+        Some(RelativeDirection::HARD_LEFT) => ("\u{21B0}", "Turn sharp left"),
+        Some(RelativeDirection::LEFT) => ("\u{21B0}", "Turn left"),
+        Some(RelativeDirection::SLIGHTLY_LEFT) => ("\u{2196}", "Turn slightly left"),
+        Some(RelativeDirection::SLIGHTLY_RIGHT) => ("\u{2197}", "Turn slightly right"),
+        Some(RelativeDirection::RIGHT) => ("\u{21B1}", "Turn right"),
+        Some(RelativeDirection::HARD_RIGHT) => ("\u{21B1}", "Turn sharp right"),
+        Some(RelativeDirection::CIRCLE_CLOCKWISE)
+        | Some(RelativeDirection::CIRCLE_COUNTERCLOCKWISE) => ("\u{21BB}", "Take the roundabout"),
+        Some(RelativeDirection::ELEVATOR) => ("\u{2195}", "Take the elevator"),
+        Some(RelativeDirection::UTURN_LEFT) | Some(RelativeDirection::UTURN_RIGHT) => {
+            ("\u{21B6}", "Make a U-turn")
+        }
+        Some(RelativeDirection::ENTER_STATION) => ("\u{2193}", "Enter the station"),
+        Some(RelativeDirection::EXIT_STATION) => ("\u{2191}", "Exit the station"),
+        Some(RelativeDirection::FOLLOW_SIGNS) => ("\u{2192}", "Follow the signs"),
+        _ => ("\u{2191}", "Continue"),
+    };
+    let preposition = match step.relative_direction {
+        Some(RelativeDirection::DEPART) | Some(RelativeDirection::CONTINUE) | None => "along",
+        _ => "onto",
+    };
+    let street = step.street_name.as_deref().filter(|_| !step.bogus_name.unwrap_or(false));
+    match street {
+        Some(street) => format!(
+            "{} {} {} {} ({})",
+            icon,
+            action,
+            preposition,
+            street,
+            format_distance(step.distance.unwrap_or(0.0))
+        ),
+        None => format!("{} {} ({})", icon, action, format_distance(step.distance.unwrap_or(0.0))),
+    }
+}
+
+fn first_transit_leg(
+    itinerary: &PlanQueryPlanItineraries,
+) -> Option<&PlanQueryPlanItinerariesLegs> {
+    itinerary.legs.iter().flatten().find(|leg| is_transit(&leg_mode(leg)))
+}
+
+async fn fetch_departures(
+    client: &Client,
+    config: &Config,
+    leg: &PlanQueryPlanItinerariesLegs,
+) -> Result<Vec<u64>> {
+    let (Some(route), Some(stop), Some(start_time)) =
+        (leg.route.as_ref(), leg.from.stop.as_ref(), leg.start_time)
+    else {
+        return Ok(vec![]);
+    };
+    let body = DeparturesQuery::build_query(departures_query::Variables {
+        stop: stop.gtfs_id.clone(),
+        start_time: Some(start_time / 1000),
+    });
+    let request = config.authorize(client.post(&config.routing_url)).json(&body);
+    let response: Response<departures_query::ResponseData> =
+        send_timed(request, "departures").await?.error_for_status()?.json().await?;
+    let stop = response
+        .data
+        .and_then(|data| data.stop)
+        .ok_or_else(|| anyhow!("Stop missing from response"))?;
+    let mut departures: Vec<i64> = stop
+        .stoptimes_for_patterns
+        .iter()
+        .flatten()
+        .flatten()
+        .filter(|stoptimes| {
+            stoptimes.pattern.as_ref().is_some_and(|pattern| pattern.route.gtfs_id == route.gtfs_id)
+        })
+        .flat_map(|stoptimes| stoptimes.stoptimes.iter().flatten().flatten())
+        .filter_map(|stoptime| Some(stoptime.service_day? as i64 + stoptime.realtime_departure?))
+        .filter(|&departure| departure > start_time as i64 / 1000)
+        .collect();
+    departures.sort_unstable();
+    Ok(departures.iter().take(2).map(|&departure| departure as u64 * 1000).collect())
+}
+
+fn departures_of<'a>(
+    departures: &'a Departures,
+    itinerary: &PlanQueryPlanItineraries,
+) -> &'a [u64] {
+    departures.get(&itinerary_key(itinerary)).map_or(&[], Vec::as_slice)
+}
+
+fn format_departures(departures: &[u64], clock: Clock) -> String {
+    let times: Vec<_> =
+        departures.iter().map(|&departure| format_time(Some(departure), clock)).collect();
+    times.join(", ")
+}
+
+fn countdown_span(itinerary: &PlanQueryPlanItineraries) -> Span<'static> {
+    let departure =
+        first_transit_leg(itinerary).and_then(|leg| leg.start_time).or(itinerary.start_time);
+    let Some(departure) = departure else {
+        return Span::raw("");
+    };
+    let remaining = (departure as i64 - Local::now().timestamp_millis()) / 1000;
+    if remaining < 0 {
+        return Span::styled("departed", Style::default().add_modifier(Modifier::DIM));
+    }
+    let style = if remaining < COUNTDOWN_WARNING_SECS {
+        Style::default().fg(Color::Red)
+    } else {
+        Style::default()
+    };
+    Span::styled(
+        format!("leaves in {}", format_duration(&Duration::from_secs(remaining as u64))),
+        style,
+    )
+}
+
+fn active_alerts(
+    leg: &PlanQueryPlanItinerariesLegs,
+) -> Vec<&PlanQueryPlanItinerariesLegsRouteAlerts> {
+    let start = leg.start_time.map(|time| time / 1000);
+    leg.route
+        .iter()
+        .flat_map(|route| route.alerts.iter().flatten().flatten())
+        .filter(|alert| match start {
+            Some(start) => {
+                alert.effective_start_date.is_none_or(|date| date <= start)
+                    && alert.effective_end_date.is_none_or(|date| start <= date)
+            }
+            None => true,
+        })
+        .collect()
+}
+
+// Legs narrower than this get widened so their labels stay readable
+const MIN_LEG_WIDTH: u16 = 6;
+
+fn leg_widths(durations: &[f64], width: u16) -> Vec<u16> {
+    // Shrink the minimum when the legs would not fit otherwise
+    let min_width = MIN_LEG_WIDTH.min(width / durations.len().max(1) as u16);
+    let mut fixed = vec![false; durations.len()];
+    let (free_width, free_total) = loop {
+        let fixed_count = fixed.iter().filter(|&&fixed| fixed).count() as u16;
+        let free_width = width.saturating_sub(fixed_count * min_width) as f64;
+        let free_total: f64 =
+            durations.iter().zip(&fixed).filter(|(_, &fixed)| !fixed).map(|(d, _)| d).sum();
+        let mut changed = false;
+        for (duration, fixed) in durations.iter().zip(fixed.iter_mut()) {
+            if !*fixed && duration / free_total * free_width < min_width as f64 {
+                *fixed = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break (free_width, free_total);
+        }
+    };
+    let mut widths: Vec<u16> = durations
+        .iter()
+        .zip(&fixed)
+        .map(
+            |(duration, &fixed)| {
+                if fixed {
+                    min_width
+                } else {
+                    (duration / free_total * free_width) as u16
+                }
+            },
+        )
+        .collect();
+    // Give the columns lost to rounding to the last leg
+    let total: u16 = widths.iter().sum();
+    if let Some(last) = widths.last_mut() {
+        *last += width.saturating_sub(total);
+    }
+    widths
+}
+
+fn render_itinerary(
+    frame: &mut Frame,
+    area: Rect,
+    itinerary: &PlanQueryPlanItineraries,
+    selected: bool,
+    badge: &str,
+    departures: &[u64],
+    config: &Config,
+) {
+    let (theme, clock) = (&config.theme, config.clock);
+    let title_style = if selected {
+        Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED)
+    } else {
+        Style::default().add_modifier(Modifier::BOLD)
+    };
+    let itinerary_block = Block::default()
+        .title(Line::from(vec![
+            Span::styled(format_title(itinerary, clock), title_style),
+            Span::raw(" "),
+            countdown_span(itinerary),
+            Span::raw(" "),
+            badge_span(badge),
+        ]))
+        .title(Title::from(format_summary(itinerary)).position(Position::Bottom))
+        .borders(Borders::ALL);
+    let itinerary_block = if selected {
+        itinerary_block
+            .border_type(BorderType::Thick)
+            .border_style(Style::default().fg(Color::Cyan))
+    } else {
+        itinerary_block
+    };
+
+    let mut legs: Vec<&Option<PlanQueryPlanItinerariesLegs>> = itinerary
+        .legs
+        .iter()
+        .filter(|leg| {
+            if let Some(leg) = leg {
+                leg.duration.unwrap_or(0.0) > config.min_leg_duration
+            } else {
+                false
+            }
+        })
+        .collect();
+    if legs.is_empty() {
+        legs = itinerary.legs.iter().filter(|leg| leg.is_some()).collect();
+    }
+    if legs.is_empty() {
+        frame.render_widget(
+            Paragraph::new("No legs").alignment(Alignment::Center).block(itinerary_block),
+            area,
+        );
+        return;
+    }
+
+    let inner = itinerary_block.inner(area);
+    let durations: Vec<f64> =
+        legs.iter().map(|leg| leg.as_ref().unwrap().duration.unwrap_or(0.0)).collect();
+    let constraints = leg_widths(&durations, inner.width)
+        .into_iter()
+        .map(Constraint::Length)
+        .collect::<Vec<Constraint>>();
+
+    let leg_chunks =
+        Layout::default().direction(Direction::Horizontal).constraints(constraints).split(inner);
+
+    let first_transit = legs.iter().position(|leg| is_transit(&leg_mode(leg.as_ref().unwrap())));
+    for (index, leg) in legs.iter().enumerate() {
+        let leg = leg.as_ref().unwrap();
+        let mode = &leg_mode(leg);
+        let from_stop_name = match leg.from.stop.as_ref() {
+            Some(stop) if first_transit == Some(index) => {
+                format!("{} {}", format_time(leg.start_time, clock), stop.name)
+            }
+            Some(stop) if is_transit(mode) => stop.name.clone(),
+            _ if is_rented_bike(leg) => leg
+                .from
+                .bike_rental_station
+                .as_ref()
+                .map(|station| station.name.clone())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let to_stop_name = match leg.to.stop.as_ref() {
+            Some(stop) if is_transit(mode) => stop.name.clone(),
+            _ if is_rented_bike(leg) => leg
+                .to
+                .bike_rental_station
+                .as_ref()
+                .map(|station| station.name.clone())
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let duration = format_duration(&Duration::from_secs_f64(leg.duration.unwrap_or(0.0)));
+
+        let mut label = vec![Span::raw(if is_rented_bike(leg) {
+            format!("{} City bike {}", mode_icon(mode), duration)
+        } else if let (Mode::WALK, Some(distance)) = (mode, leg.distance) {
+            format!("{} {} \u{00B7} {}", mode_icon(mode), duration, format_distance(distance))
+        } else if let Some(short_name) = leg
+            .route
+            .as_ref()
+            .and_then(|route| route.short_name.as_deref())
+            .filter(|_| is_transit(mode))
+        {
+            format!("{} ({}) {}", mode_icon(mode), short_name, duration)
+        } else {
+            format!("{} {}", mode_icon(mode), duration)
+        })];
+        label.extend(delay_spans(leg));
+        if !active_alerts(leg).is_empty() {
+            label.push(Span::styled(" \u{26A0}", Style::default().fg(Color::Yellow)));
+        }
+
+        let mut from_line =
+            vec![Span::styled(from_stop_name, Style::default().add_modifier(Modifier::REVERSED))];
+        if first_transit == Some(index) && !departures.is_empty() {
+            from_line.push(Span::raw(format!(" next {}", format_departures(departures, clock))));
+        }
+        frame.render_widget(
+            Paragraph::new(vec![
+                Line::from(from_line),
+                Line::from(label),
+                Line::from(Span::styled(
+                    to_stop_name,
+                    Style::default().add_modifier(Modifier::REVERSED),
+                )),
+            ])
+            .alignment(Alignment::Center)
+            .style(Style::default().bg(mode_color(theme, mode))),
+            leg_chunks[index],
+        );
+    }
+    frame.render_widget(itinerary_block, area);
+}
+
+fn badge_span(badge: &str) -> Span<'static> {
+    Span::styled(badge.to_string(), Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD))
+}
+
+fn render_compact_itinerary(
+    frame: &mut Frame,
+    area: Rect,
+    itinerary: &PlanQueryPlanItineraries,
+    selected: bool,
+    badge: &str,
+    theme: &Theme,
+    clock: Clock,
+) {
+    let mut spans = vec![Span::styled(
+        format!(
+            "{}\u{2192}{} ({})",
+            format_time(itinerary.start_time, clock),
+            format_time(itinerary.end_time, clock),
+            itinerary.duration.map_or_else(
+                || "??".to_string(),
+                |duration| format_duration(&Duration::from_secs(duration))
+            )
+        ),
+        Style::default().add_modifier(Modifier::BOLD),
+    )];
+    for leg in itinerary.legs.iter().flatten() {
+        let mode = &leg_mode(leg);
+        let route = leg.route.as_ref().and_then(|route| route.short_name.as_deref());
+        spans.push(Span::raw(" "));
+        spans.push(match route {
+            Some(route) if is_transit(mode) => Span::styled(
+                format!("{}{}", mode_icon(mode), route),
+                Style::default().fg(mode_color(theme, mode)),
+            ),
+            _ => Span::raw(mode_icon(mode)),
+        });
+    }
+    spans.push(Span::raw(" "));
+    spans.push(countdown_span(itinerary));
+    spans.push(Span::raw(" "));
+    spans.push(badge_span(badge));
+    let style =
+        if selected { Style::default().add_modifier(Modifier::REVERSED) } else { Style::default() };
+    frame.render_widget(Paragraph::new(Line::from(spans)).style(style), area);
+}
+
+fn intermediate_stop_lines(itinerary: &PlanQueryPlanItineraries) -> Vec<Line<'static>> {
+    itinerary
+        .legs
+        .iter()
+        .flatten()
+        .filter(|leg| is_transit(&leg_mode(leg)))
+        .map(|leg| {
+            let stops: Vec<&str> = leg
+                .intermediate_stops
+                .iter()
+                .flatten()
+                .flatten()
+                .map(|stop| stop.name.as_str())
+                .collect();
+            Line::from(format!(
+                "{} {}: {}",
+                mode_icon(&leg_mode(leg)),
+                leg.route
+                    .as_ref()
+                    .and_then(|route| route.short_name.as_deref())
+                    .unwrap_or_default(),
+                if stops.is_empty() {
+                    "no intermediate stops".to_string()
+                } else {
+                    stops.join(", ")
+                }
+            ))
+        })
+        .collect()
+}
+
+fn render_map(
+    frame: &mut Frame,
+    area: Rect,
+    itinerary: &PlanQueryPlanItineraries,
+    theme: &Theme,
+    clock: Clock,
+) {
+    let legs: Vec<(Color, Vec<(f64, f64)>)> = itinerary
+        .legs
+        .iter()
+        .flatten()
+        .filter_map(|leg| {
+            let points = leg.leg_geometry.as_ref()?.points.as_deref()?;
+            Some((mode_color(theme, leg.mode.as_ref()?), gpx::decode_polyline(points).ok()?))
+        })
+        .collect();
+    let points = legs.iter().flat_map(|(_, points)| points);
+    let bounds = |coordinate: fn(&(f64, f64)) -> f64| {
+        let (min, max) = points
+            .clone()
+            .map(coordinate)
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+                (min.min(value), max.max(value))
+            });
+        let padding = ((max - min) * 0.05).max(0.001);
+        [min - padding, max + padding]
+    };
+
+    let map_block = Block::default()
+        .title(Span::styled(
+            format_title(itinerary, clock),
+            Style::default().add_modifier(Modifier::BOLD),
+        ))
+        .borders(Borders::ALL);
+    if legs.is_empty() {
+        frame.render_widget(
+            Paragraph::new("No map available").alignment(Alignment::Center).block(map_block),
+            area,
+        );
+        return;
+    }
+    let map = Canvas::default()
+        .block(map_block)
+        .marker(Marker::Braille)
+        .x_bounds(bounds(|&(_, lon)| lon))
+        .y_bounds(bounds(|&(lat, _)| lat))
+        .paint(|ctx| {
+            for (color, points) in &legs {
+                for segment in points.windows(2) {
+                    let ((lat1, lon1), (lat2, lon2)) = (segment[0], segment[1]);
+                    ctx.draw(&CanvasLine { x1: lon1, y1: lat1, x2: lon2, y2: lat2, color: *color });
+                }
+            }
+        });
+    frame.render_widget(map, area);
+}
+
+fn format_elevation(itinerary: &PlanQueryPlanItineraries) -> Option<String> {
+    let gained = itinerary.elevation_gained.unwrap_or(0.0).round();
+    let lost = itinerary.elevation_lost.unwrap_or(0.0).round();
+    if gained < 1.0 && lost < 1.0 {
+        return None;
+    }
+    Some(format!("\u{2197} {:.0} m up \u{00B7} \u{2198} {:.0} m down", gained, lost))
+}
+
+fn render_details(
+    frame: &mut Frame,
+    area: Rect,
+    itinerary: &PlanQueryPlanItineraries,
+    departures: &[u64],
+    scroll: u16,
+    theme: &Theme,
+    clock: Clock,
+) {
+    let mut lines = vec![];
+    if let Some(elevation) = format_elevation(itinerary) {
+        lines.push(Line::from(elevation));
+        lines.push(Line::from(""));
+    }
+    let first_transit = itinerary.legs.iter().flatten().position(|leg| is_transit(&leg_mode(leg)));
+    for (index, leg) in itinerary.legs.iter().flatten().enumerate() {
+        let mode = &leg_mode(leg);
+        let from_name = leg
+            .from
+            .stop
+            .as_ref()
+            .map(|stop| stop.name.clone())
+            .or_else(|| leg.from.name.clone())
+            .unwrap_or_default();
+        let to_name = leg
+            .to
+            .stop
+            .as_ref()
+            .map(|stop| stop.name.clone())
+            .or_else(|| leg.to.name.clone())
+            .unwrap_or_default();
+
+        lines.push(Line::from(vec![
+            Span::raw(format!("{}  ", format_time(leg.start_time, clock))),
+            Span::styled(from_name, Style::default().add_modifier(Modifier::BOLD)),
+        ]));
+        let duration = format_duration(&Duration::from_secs_f64(leg.duration.unwrap_or(0.0)));
+        let mut label = vec![Span::styled(
+            match leg.route.as_ref() {
+                Some(route) if is_transit(mode) => format!(
+                    "       {} {} {} ({})",
+                    mode_icon(mode),
+                    route.short_name.as_deref().unwrap_or_default(),
+                    route.long_name.as_deref().unwrap_or_default(),
+                    duration
+                ),
+                _ if is_rented_bike(leg) => {
+                    format!("       {} City bike ({})", mode_icon(mode), duration)
+                }
+                _ => format!("       {} {}", mode_icon(mode), duration),
+            },
+            if is_transit(mode) {
+                Style::default().fg(mode_color(theme, mode))
+            } else {
+                Style::default()
+            },
+        )];
+        label.extend(delay_spans(leg));
+        lines.push(Line::from(label));
+        for alert in active_alerts(leg) {
+            if let Some(header) = &alert.alert_header_text {
+                lines.push(Line::from(Span::styled(
+                    format!("         \u{26A0} {}", header),
+                    Style::default().fg(Color::Yellow),
+                )));
+            }
+            lines.push(Line::from(Span::styled(
+                format!("         {}", alert.alert_description_text),
+                Style::default().fg(Color::Yellow).add_modifier(Modifier::DIM),
+            )));
+        }
+        if first_transit == Some(index) && !departures.is_empty() {
+            lines.push(Line::from(Span::styled(
+                format!("         next: {}", format_departures(departures, clock)),
+                Style::default().add_modifier(Modifier::DIM),
+            )));
+        }
+        if let Some(station) = leg.from.bike_rental_station.as_ref().filter(|_| is_rented_bike(leg))
+        {
+            lines.push(Line::from(format!("         Pick up a city bike at {}", station.name)));
+        }
+        for step in leg.steps.iter().flatten().flatten() {
+            lines.push(Line::from(Span::styled(
+                format!("         {}", step_instruction(step)),
+                Style::default().add_modifier(Modifier::DIM),
+            )));
+        }
+        if let Some(station) = leg.to.bike_rental_station.as_ref().filter(|_| is_rented_bike(leg)) {
+            lines.push(Line::from(format!("         Return the city bike at {}", station.name)));
+        }
+        for stop in leg.intermediate_stops.iter().flatten().flatten() {
+            lines.push(Line::from(Span::styled(
+                format!("         \u{00B7} {}", stop.name),
+                Style::default().add_modifier(Modifier::DIM),
+            )));
+        }
+        let mut arrival = vec![
+            Span::raw(format!("{}  ", format_time(leg.end_time, clock))),
+            Span::styled(to_name, Style::default().add_modifier(Modifier::BOLD)),
+        ];
+        if leg.intermediate_place == Some(true) {
+            arrival.push(Span::styled(" (via)", Style::default().add_modifier(Modifier::DIM)));
+        }
+        lines.push(Line::from(arrival));
+        lines.push(Line::from(""));
+    }
+
+    let details_block = Paragraph::new(lines).scroll((scroll, 0)).block(
+        Block::default()
+            .title(Span::styled(
+                format_title(itinerary, clock),
+                Style::default().add_modifier(Modifier::BOLD),
+            ))
+            .title(Title::from(format_summary(itinerary)).position(Position::Bottom))
+            .borders(Borders::ALL),
+    );
+    frame.render_widget(details_block, area);
+}
+
+// Fails rather than overwriting an earlier export
+fn write_export(extension: &str, contents: &str) -> Result<String> {
+    let path = format!("itinerary-{}.{}", Local::now().format("%Y%m%d-%H%M%S"), extension);
+    let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(path)
+}
+
+fn export_json(itinerary: &PlanQueryPlanItineraries) -> Result<String> {
+    write_export("json", &serde_json::to_string_pretty(itinerary)?)
+}
+
+fn export_gpx(itinerary: &PlanQueryPlanItineraries, name: &str) -> Result<String> {
+    let segments: Vec<_> = itinerary
+        .legs
+        .iter()
+        .flatten()
+        .filter_map(|leg| leg.leg_geometry.as_ref()?.points.as_deref())
+        .map(gpx::decode_polyline)
+        .collect::<Result<_>>()?;
+    if segments.is_empty() {
+        return Err(anyhow!("Itinerary has no geometry"));
+    }
+    write_export("gpx", &gpx::write_gpx(name, &segments))
+}
+
+fn export_notice(
+    itinerary: Option<&PlanQueryPlanItineraries>,
+    export: impl FnOnce(&PlanQueryPlanItineraries) -> Result<String>,
+) -> String {
+    match itinerary.map(export) {
+        Some(Ok(path)) => format!("Saved to {}", path),
+        Some(Err(error)) => {
+            warn!("Failed to export itinerary: {:?}", error);
+            format!("Export failed: {}", error)
+        }
+        None => "Nothing to export".to_string(),
+    }
+}
+
+fn mode_name(mode: &Mode) -> String {
+    match mode {
+        Mode::WALK => "walk".to_string(),
+        Mode::BICYCLE => "bike".to_string(),
+        Mode::BUS => "Bus".to_string(),
+        Mode::TRAM => "Tram".to_string(),
+        Mode::RAIL => "Train".to_string(),
+        Mode::SUBWAY => "Metro".to_string(),
+        Mode::FERRY => "Ferry".to_string(),
+        mode => format!("{:?}", mode),
+    }
+}
+
+fn format_share(itinerary: &PlanQueryPlanItineraries, clock: Clock) -> String {
+    let mut parts: Vec<String> = itinerary
+        .legs
+        .iter()
+        .flatten()
+        .filter_map(|leg| {
+            let mode = leg.mode.as_ref()?;
+            if is_transit(mode) {
+                let route = leg.route.as_ref().and_then(|route| route.short_name.as_deref());
+                Some(format!(
+                    "{} {} {} \u{2192} {}",
+                    format_time(leg.start_time, clock),
+                    mode_name(mode),
+                    route.unwrap_or_default(),
+                    format_time(leg.end_time, clock)
+                ))
+            } else {
+                let duration = format_duration(&Duration::from_secs_f64(leg.duration?));
+                Some(format!("{} {}", mode_name(mode), duration))
+            }
+        })
+        .collect();
+    parts.push(format!("arrive {}", format_time(itinerary.end_time, clock)));
+    parts.join(", ")
+}
+
+// The clipboard is kept alive because X11 only serves its contents while the owner exists
+fn copy_notice(
+    clipboard: &mut Option<Clipboard>,
+    itinerary: Option<&PlanQueryPlanItineraries>,
+    clock: Clock,
+) -> String {
+    let Some(itinerary) = itinerary else {
+        return "Nothing to copy".to_string();
     };
-    let to_coordinates = InputCoordinates {
-        lat: to.geometry.coordinates[1],
-        lon: to.geometry.coordinates[0],
-        address: Some(to.properties.label.clone()),
-        location_slack: None,
+    let result = match clipboard {
+        Some(clipboard) => Ok(clipboard),
+        None => Clipboard::new().map(|new| clipboard.insert(new)),
     };
+    match result.and_then(|clipboard| clipboard.set_text(format_share(itinerary, clock))) {
+        Ok(()) => "Copied".to_string(),
+        Err(error) => {
+            warn!("Failed to copy itinerary: {:?}", error);
+            "Copy failed".to_string()
+        }
+    }
+}
 
+fn request_refresh(updating: &AtomicBool, refresh_notify: &Notify) {
+    updating.store(true, Relaxed);
+    refresh_notify.notify_one();
+}
+
+pub enum Exit {
+    Quit,
+    Back,
+}
+
+pub async fn get_itinerary<B: Backend>(
+    terminal: &mut Terminal<B>,
+    client: &Client,
+    config: &Config,
+    language: &RwLock<Language>,
+    trip: Trip,
+    trip_time: TripTime,
+) -> Result<Exit> {
+    let Trip { from, via, to } = trip;
+    let mut parameters = PlanParameters::new(config, from, via, to, trip_time);
+    // The language is shared with the location search and kept between trips
+    parameters.language = *language.read().await;
+    let parameters = Arc::new(RwLock::new(parameters));
     let itineraries = Arc::new(RwLock::new(vec![]));
+    let departures: Arc<RwLock<Departures>> = Arc::new(RwLock::new(HashMap::new()));
 
     let updating = Arc::new(AtomicBool::new(false));
-    let itineraries_task: tokio::task::JoinHandle<Result<()>> = {
+    let refresh_notify = Arc::new(Notify::new());
+    let refresh_interval = config.refresh_interval;
+    let failed = Arc::new(AtomicBool::new(false));
+    let timed_out = Arc::new(AtomicBool::new(false));
+    let rate_limited = Arc::new(AtomicBool::new(false));
+    let loaded = Arc::new(AtomicBool::new(false));
+    let transfers_exceeded = Arc::new(AtomicBool::new(false));
+    let last_updated: Arc<RwLock<Option<Instant>>> = Arc::new(RwLock::new(None));
+    let router_error: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+    let retry_secs = Arc::new(AtomicU64::new(0));
+    let cancel = CancellationToken::new();
+    let itineraries_task = {
+        let cancel = cancel.clone();
         let updating = updating.clone();
+        let failed = failed.clone();
+        let timed_out = timed_out.clone();
+        let rate_limited = rate_limited.clone();
+        let loaded = loaded.clone();
+        let transfers_exceeded = transfers_exceeded.clone();
+        let last_updated = last_updated.clone();
+        let router_error = router_error.clone();
         let itineraries = itineraries.clone();
+        let departures = departures.clone();
+        let refresh_notify = refresh_notify.clone();
+        let parameters = parameters.clone();
+        let client = client.clone();
+        let config = config.clone();
+        let retry_secs = retry_secs.clone();
         tokio::spawn(async move {
-            let client = Client::new();
-            let body = PlanQuery::build_query(plan_query::Variables {
-                from: form_coordinates,
-                to: to_coordinates,
-            });
-
-            loop {
+            let mut backoff: Option<Duration> = None;
+            'task: loop {
                 {
                     info!("Updating itineraries...");
                     updating.store(true, Relaxed);
-                    let response: Response<plan_query::ResponseData> = client
-                        .post("https://api.digitransit.fi/routing/v1/routers/hsl/index/graphql")
-                        .header("digitransit-subscription-key", include_str!("../.apikey"))
-                        .json(&body)
-                        .send()
-                        .await?
-                        .json()
-                        .await?;
-
-                    *itineraries.write().await = response.data.unwrap().plan.unwrap().itineraries;
+                    let (variables, max_transfers) = {
+                        let parameters = parameters.read().await;
+                        (parameters.variables(), parameters.max_transfers)
+                    };
+                    let result = match variables {
+                        Ok(variables) => {
+                            let body = PlanQuery::build_query(variables);
+                            tokio::select! {
+                                result = fetch_itineraries(&client, &config, &body) => result,
+                                _ = cancel.cancelled() => break,
+                            }
+                        }
+                        Err(error) => Err(error),
+                    };
+                    match result {
+                        Ok(mut result) => {
+                            // The router treats maxTransfers as a soft limit, so enforce it here
+                            // unless nothing would be left to show
+                            let within_limit = |itinerary: &Option<PlanQueryPlanItineraries>| {
+                                max_transfers.zip(itinerary.as_ref()).is_none_or(
+                                    |(max, itinerary)| transfers(itinerary) as i64 <= max,
+                                )
+                            };
+                            let exceeded = !result.iter().any(within_limit);
+                            if !exceeded {
+                                result.retain(within_limit);
+                            }
+                            transfers_exceeded.store(exceeded && !result.is_empty(), Relaxed);
+                            let mut next = Departures::new();
+                            for itinerary in result.iter().flatten() {
+                                let Some(leg) = first_transit_leg(itinerary) else { continue };
+                                let fetched = tokio::select! {
+                                    fetched = fetch_departures(&client, &config, leg) => fetched,
+                                    _ = cancel.cancelled() => break 'task,
+                                };
+                                match fetched {
+                                    Ok(fetched) => {
+                                        next.insert(itinerary_key(itinerary), fetched);
+                                    }
+                                    Err(error) => warn!("Failed to load departures: {:?}", error),
+                                }
+                            }
+                            *departures.write().await = next;
+                            *itineraries.write().await = result;
+                            failed.store(false, Relaxed);
+                            timed_out.store(false, Relaxed);
+                            rate_limited.store(false, Relaxed);
+                            loaded.store(true, Relaxed);
+                            *last_updated.write().await = Some(Instant::now());
+                            *router_error.write().await = None;
+                            backoff = None;
+                        }
+                        Err(error) => {
+                            warn!("Failed to load itineraries: {:?}", error);
+                            *router_error.write().await =
+                                error.downcast_ref::<RouterError>().map(|error| error.0.clone());
+                            failed.store(true, Relaxed);
+                            timed_out.store(
+                                error
+                                    .downcast_ref::<reqwest::Error>()
+                                    .is_some_and(|error| error.is_timeout()),
+                                Relaxed,
+                            );
+                            rate_limited.store(is_rate_limited(&error), Relaxed);
+                            backoff = Some(if is_rate_limited(&error) {
+                                // Poll less often than usual until the quota recovers
+                                backoff
+                                    .map_or(refresh_interval, |backoff| {
+                                        backoff.max(refresh_interval)
+                                    })
+                                    .mul_f64(2.0)
+                                    .min(MAX_RATE_LIMITED_BACKOFF)
+                            } else {
+                                backoff.map_or(MIN_BACKOFF, |backoff| {
+                                    (backoff * 2).min(MAX_BACKOFF).min(refresh_interval)
+                                })
+                            });
+                        }
+                    }
+                    retry_secs.store(backoff.map_or(0, |backoff| backoff.as_secs()), Relaxed);
                     updating.store(false, Relaxed);
                 }
 
-                tokio::time::sleep(Duration::from_secs(60)).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff.unwrap_or(refresh_interval)) => (),
+                    _ = refresh_notify.notified() => (),
+                    _ = cancel.cancelled() => break,
+                }
             }
         })
     };
 
-    loop {
+    // Also cancels when an error below returns early
+    let _cancel_guard = cancel.clone().drop_guard();
+
+    let mut selected: usize = 0;
+    let mut selected_key = None;
+    let mut seen_update = None;
+    let mut scroll: usize = 0;
+    let mut visible: usize = 1;
+    let mut details = false;
+    let mut compact = false;
+    let mut details_scroll: u16 = 0;
+    let mut show_map = false;
+    let mut show_stops = false;
+    let mut tick: usize = 0;
+    let keymap = &config.keymap.itinerary;
+    let help = keymap.help(HELP);
+    let mut show_help = false;
+    let mut notice: Option<(String, Instant)> = None;
+    let mut clipboard: Option<Clipboard> = None;
+    let mut itinerary_areas: Vec<(usize, Rect)> = vec![];
+
+    let exit = loop {
+        tick = tick.wrapping_add(1);
         {
-            let itineraries = itineraries.read().await;
+            let itineraries = itineraries.read().await.clone();
+            let departures = departures.read().await.clone();
+            let parameters = parameters.read().await.clone();
+            let last_updated = *last_updated.read().await;
+            let router_error = router_error.read().await.clone();
+            if last_updated != seen_update {
+                seen_update = last_updated;
+                if let Some(position) =
+                    selected_key.as_ref().and_then(|key| reselect(&itineraries, key))
+                {
+                    selected = position;
+                }
+            }
             terminal.draw(|frame| {
+                if ui::too_small(frame) {
+                    return;
+                }
                 let chunks = Layout::default()
                     .direction(Direction::Vertical)
                     .margin(1)
-                    .constraints(
-                        [
-                            vec![Constraint::Length(2)],
-                            vec![Constraint::Length(5); itineraries.len()],
-                            vec![Constraint::Max(0)],
-                        ]
-                        .concat(),
-                    )
+                    .constraints([Constraint::Length(2), Constraint::Min(0)])
                     .split(frame.size());
 
+                let stops = match itineraries.get(selected) {
+                    Some(Some(itinerary)) if show_stops => intermediate_stop_lines(itinerary),
+                    _ => vec![],
+                };
+                let body_chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .constraints([
+                        Constraint::Min(0),
+                        Constraint::Length(if stops.is_empty() {
+                            0
+                        } else {
+                            (stops.len() as u16 + 2).min(chunks[1].height / 2)
+                        }),
+                    ])
+                    .split(chunks[1]);
+
+                let itinerary_height = if compact { 1 } else { ITINERARY_HEIGHT };
+                visible = (body_chunks[0].height / itinerary_height).max(1) as usize;
+                selected = selected.min(itineraries.len().saturating_sub(1));
+                if selected < scroll {
+                    scroll = selected;
+                } else if selected >= scroll + visible {
+                    scroll = selected + 1 - visible;
+                }
+                scroll = scroll.min(itineraries.len().saturating_sub(visible));
+                let shown = itineraries.len().saturating_sub(scroll).min(visible);
+
                 let title_chunks = Layout::default()
                     .direction(Direction::Horizontal)
                     .constraints([Constraint::Ratio(1, 2), Constraint::Ratio(1, 2)])
                     .split(chunks[0]);
 
-                let title_block =
-                    Paragraph::new(format!("{} -> {}", from.properties.label, to.properties.label));
+                let title_block = Paragraph::new(parameters.route_name());
                 frame.render_widget(title_block, title_chunks[0]);
 
-                let status_block =
-                    Paragraph::new(if updating.load(Relaxed) { "Updating..." } else { "Idle" })
-                        .alignment(Alignment::Right);
+                let mut status = vec![];
+                if shown < itineraries.len() {
+                    status.push(format!("{}-{}/{}", scroll + 1, scroll + shown, itineraries.len()));
+                }
+                status.push(format!(
+                    "{} {}",
+                    if parameters.trip_time.arrive_by { "Arriving by" } else { "Leaving at" },
+                    parameters.trip_time.time.format(config.clock.format())
+                ));
+                if let Some(walk_speed) = parameters.walk_speed {
+                    status.push(format!("\u{1F6B6} {:.1} m/s", walk_speed));
+                }
+                if let Some(max_walk_distance) = parameters.max_walk_distance {
+                    status.push(format!("\u{1F6B6} max {}", format_distance(max_walk_distance)));
+                }
+                if let Some(min_transfer_time) = parameters.min_transfer_time {
+                    let min_transfer_time = Duration::from_secs(min_transfer_time as u64);
+                    status.push(format!("min {} per change", format_duration(&min_transfer_time)));
+                }
+                if let Some(max_transfers) = parameters.max_transfers {
+                    status.push(if transfers_exceeded.load(Relaxed) {
+                        let limit = format!("No routes with max {} changes", max_transfers);
+                        format!("{}, showing best available", limit)
+                    } else {
+                        format!("max {} changes", max_transfers)
+                    });
+                }
+                if parameters.num_itineraries != config.num_itineraries {
+                    status.push(format!("{} routes", parameters.num_itineraries));
+                }
+                if parameters.preference != config.preference {
+                    status.push(parameters.preference.name().to_string());
+                }
+                if parameters.language != config.language {
+                    status.push(parameters.language.code().to_uppercase());
+                }
+                if parameters.wheelchair {
+                    status.push("\u{267F} Accessible only".to_string());
+                }
+                if parameters.bike_rental {
+                    status.push("\u{1F6B2} City bikes".to_string());
+                }
+                if parameters.enabled_modes.contains(&false) {
+                    let modes: String = TRANSIT_MODES
+                        .iter()
+                        .zip(parameters.enabled_modes)
+                        .filter(|(_, enabled)| *enabled)
+                        .map(|(mode, _)| mode_icon(mode))
+                        .collect();
+                    let modes = if modes.is_empty() { "\u{1F6B6}".to_string() } else { modes };
+                    status.push(format!("Only {}", modes));
+                }
+                if let Some((message, time)) = &notice {
+                    if time.elapsed() < NOTICE_DURATION {
+                        status.push(message.clone());
+                    }
+                }
+                status.push(if updating.load(Relaxed) {
+                    format!("{} Updating", ui::spinner(tick))
+                } else if rate_limited.load(Relaxed) {
+                    format!("Rate limited, slowing down, retrying in {}s", retry_secs.load(Relaxed))
+                } else if timed_out.load(Relaxed) {
+                    format!("Request timed out, retrying in {}s", retry_secs.load(Relaxed))
+                } else if let Some(message) = router_error.filter(|_| failed.load(Relaxed)) {
+                    format!("Router error: {}, retrying in {}s", message, retry_secs.load(Relaxed))
+                } else if failed.load(Relaxed) {
+                    format!("Failed to load routes, retrying in {}s", retry_secs.load(Relaxed))
+                } else if let Some(last_updated) = last_updated {
+                    let age = Duration::from_secs(last_updated.elapsed().as_secs());
+                    format!("updated {} ago", format_duration(&age))
+                } else {
+                    "Idle".to_string()
+                });
+                let status_block = Paragraph::new(status.join(" | ")).alignment(Alignment::Right);
                 frame.render_widget(status_block, title_chunks[1]);
 
-                for (index, itinerary) in itineraries.iter().enumerate() {
-                    if let Some(itinerary) = itinerary {
-                        let itinerary_block = Block::default()
-                            .title(Span::styled(
-                                format_title(itinerary),
+                if details {
+                    if let Some(Some(itinerary)) = itineraries.get(selected) {
+                        if show_map {
+                            render_map(frame, chunks[1], itinerary, &config.theme, config.clock);
+                        } else {
+                            render_details(
+                                frame,
+                                chunks[1],
+                                itinerary,
+                                departures_of(&departures, itinerary),
+                                details_scroll,
+                                &config.theme,
+                                config.clock,
+                            );
+                        }
+                    }
+                } else {
+                    if loaded.load(Relaxed) && itineraries.iter().flatten().next().is_none() {
+                        let hint = format!(
+                            "Press {} to swap the direction, {} to pick new locations \
+                             or {} to quit",
+                            keymap.keys(ItineraryAction::Swap),
+                            keymap.keys(ItineraryAction::Back),
+                            keymap.keys(ItineraryAction::Quit)
+                        );
+                        let mut lines = vec![
+                            Line::from(Span::styled(
+                                "No routes found between these locations",
                                 Style::default().add_modifier(Modifier::BOLD),
-                            ))
-                            .borders(Borders::ALL);
-
-                        let legs: Vec<&Option<PlanQueryPlanItinerariesLegs>> = itinerary
-                            .legs
-                            .iter()
-                            .filter(|leg| {
-                                if let Some(leg) = leg {
-                                    leg.duration.unwrap() > 60.0
-                                } else {
-                                    false
-                                }
-                            })
-                            .collect();
-
-                        let constraints = legs
-                            .iter()
-                            .map(|leg| {
-                                Constraint::Ratio(
-                                    (leg.as_ref().unwrap().duration.unwrap()
-                                        / itinerary.duration.unwrap() as f64
-                                        * 100.0) as u32,
-                                    100,
-                                )
-                            })
-                            .collect::<Vec<Constraint>>();
-
-                        let leg_chunks = Layout::default()
-                            .direction(Direction::Horizontal)
-                            .constraints(constraints)
-                            .split(itinerary_block.inner(chunks[index + 1]));
-
-                        for (index, leg) in legs.iter().enumerate() {
-                            let mode = leg.as_ref().unwrap().mode.as_ref().unwrap();
-                            let from_stop_name: &str = if *mode != Mode::WALK {
-                                leg.as_ref().unwrap().from.stop.as_ref().unwrap().name.as_ref()
-                            } else {
-                                ""
-                            };
-                            let to_stop_name: &str = if *mode != Mode::WALK {
-                                leg.as_ref().unwrap().to.stop.as_ref().unwrap().name.as_ref()
-                            } else {
-                                ""
-                            };
-                            frame.render_widget(
-                                Paragraph::new(vec![
-                                    Line::from(Span::styled(
-                                        from_stop_name,
-                                        Style::default().add_modifier(Modifier::REVERSED),
-                                    )),
-                                    Line::from(Span::raw(if *mode == Mode::WALK {
-                                        format!(
-                                            "\u{1F6B6} {}",
-                                            format_duration(&Duration::from_secs_f64(
-                                                leg.as_ref().unwrap().duration.unwrap()
-                                            ))
-                                        )
-                                    } else {
-                                        format!(
-                                            "{} ({}) {}",
-                                            match mode {
-                                                Mode::BUS => "\u{1F68C}",
-                                                Mode::RAIL => "\u{1F686}",
-                                                Mode::SUBWAY => "\u{1F687}",
-                                                _ => "none",
-                                            },
-                                            leg.as_ref()
-                                                .unwrap()
-                                                .route
-                                                .as_ref()
-                                                .unwrap()
-                                                .short_name
-                                                .as_ref()
-                                                .unwrap(),
-                                            format_duration(&Duration::from_secs_f64(
-                                                leg.as_ref().unwrap().duration.unwrap()
-                                            ))
-                                        )
-                                    })),
-                                    Line::from(Span::styled(
-                                        to_stop_name,
-                                        Style::default().add_modifier(Modifier::REVERSED),
-                                    )),
-                                ])
-                                .alignment(Alignment::Center)
-                                .style(Style::default().bg(
-                                    match mode {
-                                        Mode::WALK => Color::Black,
-                                        Mode::BUS => Color::Blue,
-                                        Mode::RAIL => Color::Magenta,
-                                        _ => Color::Black,
-                                    },
-                                )),
-                                leg_chunks[index],
+                            )),
+                            Line::from(hint),
+                        ];
+                        if parameters.max_walk_distance.is_some() {
+                            lines.push(Line::from(format!(
+                                "Press {} to allow more walking",
+                                keymap.keys(ItineraryAction::MoreWalking)
+                            )));
+                        }
+                        let message_block = Paragraph::new(lines).alignment(Alignment::Center);
+                        let message_area = Layout::default()
+                            .direction(Direction::Vertical)
+                            .constraints([
+                                Constraint::Percentage(50),
+                                Constraint::Length(3),
+                                Constraint::Min(0),
+                            ])
+                            .split(body_chunks[0])[1];
+                        frame.render_widget(message_block, message_area);
+                    }
+
+                    itinerary_areas.clear();
+                    let badges = badges(&itineraries);
+                    let itinerary_chunks = Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints(
+                            [
+                                vec![Constraint::Length(itinerary_height); shown],
+                                vec![Constraint::Max(0)],
+                            ]
+                            .concat(),
+                        )
+                        .split(body_chunks[0]);
+
+                    let page = itineraries.iter().skip(scroll).take(shown);
+                    for (index, itinerary) in page.enumerate() {
+                        if let Some(itinerary) = itinerary {
+                            itinerary_areas.push((scroll + index, itinerary_chunks[index]));
+                            if compact {
+                                render_compact_itinerary(
+                                    frame,
+                                    itinerary_chunks[index],
+                                    itinerary,
+                                    scroll + index == selected,
+                                    &badges[scroll + index],
+                                    &config.theme,
+                                    config.clock,
+                                );
+                                continue;
+                            }
+                            render_itinerary(
+                                frame,
+                                itinerary_chunks[index],
+                                itinerary,
+                                scroll + index == selected,
+                                &badges[scroll + index],
+                                departures_of(&departures, itinerary),
+                                config,
                             );
                         }
+                    }
 
-                        frame.render_widget(itinerary_block, chunks[index + 1]);
+                    if !stops.is_empty() {
+                        let block =
+                            Block::default().title("Intermediate stops").borders(Borders::ALL);
+                        let stops_block =
+                            Paragraph::new(stops).wrap(Wrap { trim: true }).block(block);
+                        frame.render_widget(stops_block, body_chunks[1]);
                     }
                 }
+
+                if show_help {
+                    help::render_help(frame, &help);
+                }
             })?;
+            selected_key = itineraries.get(selected).and_then(Option::as_ref).map(itinerary_key);
         }
 
         if event::poll(Duration::from_millis(16))? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char('q') => break,
-                    KeyCode::Esc => break,
+            let action = match event::read()? {
+                Event::Key(_) if show_help => {
+                    show_help = false;
+                    None
+                }
+                Event::Key(key) => keymap.action(Key::from(key)),
+                Event::Resize(..) => {
+                    terminal.clear()?;
+                    None
+                }
+                Event::Mouse(mouse) => match mouse.kind {
+                    MouseEventKind::ScrollUp => Some(ItineraryAction::Up),
+                    MouseEventKind::ScrollDown => Some(ItineraryAction::Down),
+                    MouseEventKind::Down(MouseButton::Left) if !details => itinerary_areas
+                        .iter()
+                        .find(|(_, area)| area.intersects(Rect::new(mouse.column, mouse.row, 1, 1)))
+                        .map(|(index, _)| {
+                            selected = *index;
+                            ItineraryAction::Details
+                        }),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(action) = action {
+                if details {
+                    match action {
+                        ItineraryAction::Quit => break Exit::Quit,
+                        ItineraryAction::Close => details = false,
+                        ItineraryAction::Help => show_help = true,
+                        ItineraryAction::ExportGpx => {
+                            let name = parameters.read().await.route_name();
+                            let itineraries = itineraries.read().await;
+                            let itinerary = itineraries.get(selected).and_then(Option::as_ref);
+                            let export = |itinerary: &_| export_gpx(itinerary, &name);
+                            notice = Some((export_notice(itinerary, export), Instant::now()));
+                        }
+                        ItineraryAction::ExportJson => {
+                            let itineraries = itineraries.read().await;
+                            let itinerary = itineraries.get(selected).and_then(Option::as_ref);
+                            notice = Some((export_notice(itinerary, export_json), Instant::now()));
+                        }
+                        ItineraryAction::Copy => {
+                            let itineraries = itineraries.read().await;
+                            let itinerary = itineraries.get(selected).and_then(Option::as_ref);
+                            notice = Some((
+                                copy_notice(&mut clipboard, itinerary, config.clock),
+                                Instant::now(),
+                            ));
+                        }
+                        ItineraryAction::Map => show_map = !show_map,
+                        ItineraryAction::Up => details_scroll = details_scroll.saturating_sub(1),
+                        ItineraryAction::Down => details_scroll = details_scroll.saturating_add(1),
+                        ItineraryAction::First => details_scroll = 0,
+                        _ => (),
+                    }
+                    continue;
+                }
+                match action {
+                    ItineraryAction::Quit => break Exit::Quit,
+                    ItineraryAction::Close => break Exit::Quit,
+                    ItineraryAction::Back => break Exit::Back,
+                    ItineraryAction::Help => show_help = true,
+                    ItineraryAction::Details => {
+                        details = true;
+                        details_scroll = 0;
+                    }
+                    ItineraryAction::Up => selected = selected.saturating_sub(1),
+                    ItineraryAction::Down => selected += 1,
+                    ItineraryAction::PageUp => selected = selected.saturating_sub(visible),
+                    ItineraryAction::PageDown => selected += visible,
+                    ItineraryAction::First => selected = 0,
+                    ItineraryAction::Last => selected = usize::MAX,
+                    ItineraryAction::Refresh => request_refresh(&updating, &refresh_notify),
+                    ItineraryAction::Swap => {
+                        {
+                            let mut parameters = parameters.write().await;
+                            let parameters = &mut *parameters;
+                            std::mem::swap(&mut parameters.from, &mut parameters.to);
+                            parameters.via.reverse();
+                        }
+                        itineraries.write().await.clear();
+                        loaded.store(false, Relaxed);
+                        *last_updated.write().await = None;
+                        selected = 0;
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    action @ (ItineraryAction::Later | ItineraryAction::Earlier) => {
+                        let later = action == ItineraryAction::Later;
+                        let trip_time = paged_trip_time(&itineraries.read().await, later);
+                        if let Some(trip_time) = trip_time {
+                            parameters.write().await.trip_time = trip_time;
+                            itineraries.write().await.clear();
+                            loaded.store(false, Relaxed);
+                            *last_updated.write().await = None;
+                            selected = 0;
+                            request_refresh(&updating, &refresh_notify);
+                        }
+                    }
+                    ItineraryAction::Stops => show_stops = !show_stops,
+                    ItineraryAction::Language => {
+                        let mut parameters = parameters.write().await;
+                        parameters.language = parameters.language.next();
+                        *language.write().await = parameters.language;
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::More => {
+                        let mut parameters = parameters.write().await;
+                        parameters.num_itineraries =
+                            (parameters.num_itineraries + 1).min(NUM_ITINERARIES_RANGE.1);
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::Fewer => {
+                        let mut parameters = parameters.write().await;
+                        parameters.num_itineraries =
+                            (parameters.num_itineraries - 1).max(NUM_ITINERARIES_RANGE.0);
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::Wheelchair => {
+                        let mut parameters = parameters.write().await;
+                        parameters.wheelchair = !parameters.wheelchair;
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::ToggleMode(index) => {
+                        let mut parameters = parameters.write().await;
+                        parameters.enabled_modes[index] = !parameters.enabled_modes[index];
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::LessWalking => {
+                        let mut parameters = parameters.write().await;
+                        parameters.max_walk_distance = Some(match parameters.max_walk_distance {
+                            Some(distance) => {
+                                (distance - WALK_DISTANCE_STEP).max(MAX_WALK_DISTANCE_RANGE.0)
+                            }
+                            None => DEFAULT_MAX_WALK_DISTANCE,
+                        });
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::MoreWalking => {
+                        let mut parameters = parameters.write().await;
+                        parameters.max_walk_distance = parameters
+                            .max_walk_distance
+                            .map(|distance| distance + WALK_DISTANCE_STEP)
+                            .filter(|&distance| distance <= MAX_WALK_DISTANCE_RANGE.1);
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::ShorterTransfers => {
+                        let mut parameters = parameters.write().await;
+                        parameters.min_transfer_time = parameters
+                            .min_transfer_time
+                            .map(|time| time - TRANSFER_TIME_STEP)
+                            .filter(|&time| time > MIN_TRANSFER_TIME_RANGE.0);
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::LongerTransfers => {
+                        let mut parameters = parameters.write().await;
+                        parameters.min_transfer_time = Some(
+                            (parameters.min_transfer_time.unwrap_or(0) + TRANSFER_TIME_STEP)
+                                .min(MIN_TRANSFER_TIME_RANGE.1),
+                        );
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::Compact => compact = !compact,
+                    ItineraryAction::Preference => {
+                        let mut parameters = parameters.write().await;
+                        parameters.preference = parameters.preference.next();
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::MaxTransfers => {
+                        let mut parameters = parameters.write().await;
+                        parameters.max_transfers = match parameters.max_transfers {
+                            None => Some(0),
+                            Some(transfers) if transfers < MAX_TRANSFERS_LIMIT => {
+                                Some(transfers + 1)
+                            }
+                            Some(_) => None,
+                        };
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::CityBikes => {
+                        let mut parameters = parameters.write().await;
+                        parameters.bike_rental = !parameters.bike_rental;
+                        request_refresh(&updating, &refresh_notify);
+                    }
+                    ItineraryAction::ExportJson => {
+                        let itineraries = itineraries.read().await;
+                        let itinerary = itineraries.get(selected).and_then(Option::as_ref);
+                        notice = Some((export_notice(itinerary, export_json), Instant::now()));
+                    }
+                    ItineraryAction::Copy => {
+                        let itineraries = itineraries.read().await;
+                        let itinerary = itineraries.get(selected).and_then(Option::as_ref);
+                        notice = Some((
+                            copy_notice(&mut clipboard, itinerary, config.clock),
+                            Instant::now(),
+                        ));
+                    }
                     _ => (),
                 }
             }
         }
+    };
+
+    cancel.cancel();
+    itineraries_task.await?;
+
+    Ok(exit)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use serde_json::json;
+    use wiremock::{
+        matchers::{body_partial_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+    use crate::get_location::{Geometry, Properties};
+
+    #[test]
+    fn leg_widths_keep_tiny_legs_visible() {
+        let widths = leg_widths(&[60.0, 3600.0, 30.0], 80);
+        assert_eq!(widths.iter().sum::<u16>(), 80);
+        assert!(widths[0] >= 1 && widths[2] >= 1);
+        assert_eq!(widths[0], MIN_LEG_WIDTH);
     }
 
-    itineraries_task.abort();
+    #[test]
+    fn leg_widths_fit_narrow_areas() {
+        let widths = leg_widths(&[600.0, 600.0, 600.0, 600.0], 3);
+        assert_eq!(widths.len(), 4);
+        assert_eq!(widths.iter().sum::<u16>(), 3);
+    }
+
+    #[test]
+    fn leg_widths_distribute_rounding_remainder() {
+        let widths = leg_widths(&[100.0, 100.0, 100.0], 80);
+        assert_eq!(widths, vec![26, 26, 28]);
+        assert_eq!(widths.iter().sum::<u16>(), 80);
+    }
 
-    Ok(())
+    fn feature(label: &str, lat: f64, lon: f64) -> Feature {
+        Feature {
+            geometry: Geometry { coordinates: vec![lon, lat] },
+            properties: Properties { label: label.to_string() },
+        }
+    }
+
+    fn trip_time() -> TripTime {
+        TripTime {
+            time: NaiveDate::from_ymd_opt(2024, 5, 6).unwrap().and_hms_opt(8, 30, 0).unwrap(),
+            arrive_by: false,
+        }
+    }
+
+    async fn plan_from(server: &MockServer) -> Result<Vec<PlanQueryPlanItineraries>> {
+        let config = Config::with_endpoints(
+            format!("{}/graphql", server.uri()),
+            format!("{}/autocomplete", server.uri()),
+        );
+        plan(
+            &Client::new(),
+            &config,
+            feature("Kamppi", 60.1690, 24.9316),
+            vec![],
+            feature("Pasila", 60.1989, 24.9335),
+            trip_time(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn plan_parses_itineraries() {
+        let place = |name| json!({ "name": name, "stop": null, "bikeRentalStation": null });
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .and(body_partial_json(json!({
+                "variables": {
+                    "from": { "lat": 60.1690, "lon": 24.9316 },
+                    "date": "2024-05-06",
+                    "time": "08:30:00"
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "plan": {
+                        "itineraries": [{
+                            "startTime": 1_714_973_400_000u64,
+                            "endTime": 1_714_974_000_000u64,
+                            "duration": 600,
+                            "emissionsPerPerson": null,
+                            "elevationGained": 0.0,
+                            "elevationLost": 0.0,
+                            "fares": [],
+                            "legs": [{
+                                "startTime": 1_714_973_400_000u64,
+                                "endTime": 1_714_974_000_000u64,
+                                "duration": 600.0,
+                                "distance": 3200.0,
+                                "mode": "RAIL",
+                                "realTime": false,
+                                "departureDelay": 0,
+                                "arrivalDelay": 0,
+                                "rentedBike": false,
+                                "intermediatePlace": false,
+                                "route": {
+                                    "gtfsId": "HSL:3001I",
+                                    "shortName": "I",
+                                    "longName": "Helsinki - Pasila",
+                                    "mode": "RAIL",
+                                    "alerts": []
+                                },
+                                "intermediateStops": [],
+                                "legGeometry": { "points": "" },
+                                "steps": [],
+                                "from": place("Kamppi"),
+                                "to": place("Pasila")
+                            }]
+                        }]
+                    }
+                }
+            })))
+            .mount(&server)
+            .await;
+
+        let itineraries = plan_from(&server).await.unwrap();
+
+        assert_eq!(itineraries.len(), 1);
+        assert_eq!(itineraries[0].duration, Some(600));
+        let leg = itineraries[0].legs[0].as_ref().unwrap();
+        assert_eq!(leg.mode, Some(Mode::RAIL));
+        assert_eq!(leg.route.as_ref().unwrap().short_name.as_deref(), Some("I"));
+    }
+
+    #[test]
+    fn variables_follow_the_preference() {
+        let config = Config::with_endpoints(String::new(), String::new());
+        let mut parameters = PlanParameters::new(
+            &config,
+            feature("Kamppi", 60.1690, 24.9316),
+            vec![],
+            feature("Pasila", 60.1989, 24.9335),
+            trip_time(),
+        );
+        let variables = parameters.variables().unwrap();
+        assert_eq!(variables.optimize, Some(OptimizeType::QUICK));
+        assert_eq!((variables.transfer_penalty, variables.walk_reluctance), (None, None));
+
+        parameters.preference = Preference::FewestTransfers;
+        let variables = parameters.variables().unwrap();
+        assert_eq!(variables.optimize, Some(OptimizeType::TRANSFERS));
+        assert_eq!(variables.transfer_penalty, Some(FEWEST_TRANSFERS_PENALTY));
+
+        parameters.preference = Preference::LeastWalking;
+        let variables = parameters.variables().unwrap();
+        assert_eq!(variables.optimize, Some(OptimizeType::QUICK));
+        assert_eq!(variables.walk_reluctance, Some(LEAST_WALKING_RELUCTANCE));
+    }
+
+    #[tokio::test]
+    async fn fetch_departures_after_the_leg() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .and(body_partial_json(json!({
+                "variables": { "stop": "HSL:1040602", "startTime": 1_714_973_400u64 }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "stop": {
+                        "stoptimesForPatterns": [
+                            {
+                                "pattern": { "route": { "gtfsId": "HSL:1055" } },
+                                "stoptimes": [
+                                    { "serviceDay": 1_714_942_800u64, "realtimeDeparture": 31_000 }
+                                ]
+                            },
+                            {
+                                "pattern": { "route": { "gtfsId": "HSL:3001I" } },
+                                "stoptimes": [
+                                    { "serviceDay": 1_714_942_800u64, "realtimeDeparture": 31_800 },
+                                    { "serviceDay": 1_714_942_800u64, "realtimeDeparture": 30_600 },
+                                    { "serviceDay": 1_714_942_800u64, "realtimeDeparture": 31_200 },
+                                    { "serviceDay": 1_714_942_800u64, "realtimeDeparture": 32_400 }
+                                ]
+                            }
+                        ]
+                    }
+                }
+            })))
+            .mount(&server)
+            .await;
+        let leg: PlanQueryPlanItinerariesLegs = serde_json::from_value(json!({
+            "startTime": 1_714_973_400_000u64,
+            "mode": "RAIL",
+            "route": { "gtfsId": "HSL:3001I", "alerts": [] },
+            "from": { "name": "Kamppi", "stop": { "gtfsId": "HSL:1040602", "name": "Kamppi" } },
+            "to": { "name": "Pasila" }
+        }))
+        .unwrap();
+        let config = Config::with_endpoints(
+            format!("{}/graphql", server.uri()),
+            format!("{}/autocomplete", server.uri()),
+        );
+        let departures = fetch_departures(&Client::new(), &config, &leg).await.unwrap();
+        assert_eq!(departures, vec![1_714_974_000_000, 1_714_974_600_000]);
+    }
+
+    #[tokio::test]
+    async fn plan_fails_without_plan() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "data": { "plan": null } })),
+            )
+            .mount(&server)
+            .await;
+
+        assert!(plan_from(&server).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn plan_reports_router_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": { "plan": null },
+                "errors": [{ "message": "Unknown stop" }]
+            })))
+            .mount(&server)
+            .await;
+
+        let error = plan_from(&server).await.unwrap_err();
+        assert_eq!(error.to_string(), "Router error: Unknown stop");
+    }
+
+    #[tokio::test]
+    async fn plan_without_itineraries_is_empty() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({ "data": { "plan": { "itineraries": [] } } })),
+            )
+            .mount(&server)
+            .await;
+
+        assert!(plan_from(&server).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn format_duration_zero() {
+        assert_eq!(format_duration(&Duration::ZERO), "0s");
+    }
+
+    #[test]
+    fn format_duration_exactly_one_hour() {
+        assert_eq!(format_duration(&Duration::from_secs(3600)), "1h");
+    }
+
+    #[test]
+    fn format_duration_sub_minute() {
+        assert_eq!(format_duration(&Duration::from_secs(45)), "45s");
+    }
+
+    #[test]
+    fn format_duration_minutes_and_seconds() {
+        assert_eq!(format_duration(&Duration::from_secs(125)), "2m 5s");
+    }
+
+    #[test]
+    fn format_duration_over_a_day() {
+        assert_eq!(format_duration(&Duration::from_secs(90061)), "25h 1m 1s");
+    }
+
+    fn itinerary(
+        start_time: Option<u64>,
+        end_time: Option<u64>,
+        duration: Option<u64>,
+    ) -> PlanQueryPlanItineraries {
+        PlanQueryPlanItineraries {
+            start_time,
+            end_time,
+            duration,
+            emissions_per_person: None,
+            elevation_gained: None,
+            elevation_lost: None,
+            fares: None,
+            legs: vec![],
+        }
+    }
+
+    fn route_leg(gtfs_id: &str) -> Option<PlanQueryPlanItinerariesLegs> {
+        serde_json::from_value(json!({
+            "mode": "BUS",
+            "route": { "gtfsId": gtfs_id, "alerts": [] },
+            "from": { "name": "Kamppi" },
+            "to": { "name": "Pasila" }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn format_title_missing_start_time() {
+        let itinerary = itinerary(None, None, Some(600));
+        assert_eq!(format_title(&itinerary, Clock::TwentyFourHour), "[ ??:?? - ??:?? | 10m ]");
+    }
+
+    #[test]
+    fn badges_prefer_the_first_of_ties() {
+        let itinerary = |end_time, duration| Some(itinerary(None, Some(end_time), Some(duration)));
+        let itineraries = [itinerary(2000, 900), itinerary(1000, 600), itinerary(1000, 600)];
+        assert_eq!(badges(&itineraries), ["", "\u{26A1} fastest \u{1F3C1} earliest", ""]);
+        assert_eq!(badges(&itineraries[..1]), [""]);
+    }
+
+    #[test]
+    fn reselect_follows_the_selected_itinerary() {
+        let itinerary = |start_time, route| {
+            let mut itinerary = itinerary(Some(start_time), None, None);
+            itinerary.legs = vec![route_leg(route)];
+            Some(itinerary)
+        };
+        let before =
+            [itinerary(1000, "HSL:1055"), itinerary(2000, "HSL:1055"), itinerary(2000, "HSL:1014")];
+        let key = itinerary_key(before[2].as_ref().unwrap());
+        assert_eq!(key, (Some(2000), Some("HSL:1014".to_string())));
+        assert_eq!(reselect(&before, &key), Some(2));
+
+        let reordered =
+            [itinerary(2000, "HSL:1014"), itinerary(2000, "HSL:1055"), itinerary(1000, "HSL:1055")];
+        assert_eq!(reselect(&reordered, &key), Some(0));
+
+        let trimmed = [itinerary(2000, "HSL:1055"), itinerary(2000, "HSL:1014")];
+        assert_eq!(reselect(&trimmed, &key), Some(1));
+
+        let gone = [itinerary(2000, "HSL:1055"), itinerary(3000, "HSL:1014")];
+        assert_eq!(reselect(&gone, &key), None);
+    }
 }
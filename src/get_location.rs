@@ -1,163 +1,1132 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::anyhow;
 use anyhow::Result;
-use crossterm::event::{self, Event, KeyCode};
+use crossterm::event::{self, Event, KeyCode, KeyModifiers, MouseButton, MouseEventKind};
+use graphql_client::{GraphQLQuery, Response};
 use ratatui::{
     backend::Backend,
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Style},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    layout::{Alignment, Constraint, Direction, Layout, Margin, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{
+        block::{Position, Title},
+        Block, Borders, List, ListItem, ListState, Paragraph,
+    },
     Terminal,
 };
 use reqwest::Client;
-use serde::Deserialize;
-use tokio::sync::{Notify, RwLock};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{Notify, RwLock},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
 use unicode_width::UnicodeWidthStr;
 
+use crate::{
+    config::{Config, Language, SearchMode},
+    help,
+    http::{is_rate_limited, send_timed},
+    keymap::{Key, LocationAction},
+    persistence,
+    ui::{self, format_distance},
+};
+
 #[derive(Deserialize, Debug, Clone)]
 struct LocationResponse {
     features: Vec<Feature>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Feature {
     pub geometry: Geometry,
+    #[serde(default)]
     pub properties: Properties,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Geometry {
     pub coordinates: Vec<f64>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Properties {
+    #[serde(default)]
     pub label: String,
 }
 
-async fn get_locations(client: &Client, query: &str) -> Result<LocationResponse> {
-    let request = client
-        .get("http://api.digitransit.fi/geocoding/v1/autocomplete")
-        .header("digitransit-subscription-key", include_str!("../.apikey"))
-        .query(&[("text", query)]);
-    Ok(request.send().await?.json().await?)
-}
-
-pub async fn get_location<B: Backend>(terminal: &mut Terminal<B>) -> Result<Feature> {
-    let input = Arc::new(RwLock::new(String::new()));
-    let locations = Arc::new(RwLock::new(LocationResponse { features: vec![] }));
-
-    let input_notify = Arc::new(Notify::new());
-
-    let locations_task = {
-        let input = input.clone();
-        let locations = locations.clone();
-        let input_notify = input_notify.clone();
-        tokio::spawn(async move {
-            let client = reqwest::Client::new();
-            loop {
-                input_notify.notified().await;
-                let input = input.read().await.clone();
-                let result = get_locations(&client, &input).await;
-                if let Ok(result) = result {
-                    tracing::info!("{:?}", result);
-                    let mut locations = locations.write().await;
-                    *locations = result;
-                }
-                tokio::time::sleep(Duration::from_secs(1)).await;
+#[derive(GraphQLQuery)]
+#[graphql(
+    schema_path = "src/schema.graphql",
+    query_path = "src/queries/stops.graphql",
+    response_derives = "Debug"
+)]
+struct StopsQuery;
+
+// Stop codes are a short letter prefix followed by digits, e.g. "H2034" or "E1234"
+fn is_stop_code(query: &str) -> bool {
+    let digits = query.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    query.len() - digits.len() <= 2
+        && (3..=5).contains(&digits.len())
+        && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+fn stop_variables(query: &str) -> Option<stops_query::Variables> {
+    let query = query.trim();
+    if query.contains(':') && !query.contains(char::is_whitespace) {
+        Some(stops_query::Variables { ids: Some(vec![Some(query.to_string())]), name: None })
+    } else if is_stop_code(query) {
+        Some(stops_query::Variables { ids: None, name: Some(query.to_string()) })
+    } else {
+        None
+    }
+}
+
+async fn get_stops(
+    client: &Client,
+    config: &Config,
+    variables: stops_query::Variables,
+    query: &str,
+) -> Result<LocationResponse> {
+    let body = StopsQuery::build_query(variables);
+    let request = config.authorize(client.post(&config.routing_url)).json(&body);
+    let response: Response<stops_query::ResponseData> =
+        send_timed(request, "stops").await?.error_for_status()?.json().await?;
+    let query = query.trim();
+    let features = response
+        .data
+        .and_then(|data| data.stops)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|stop| {
+            stop.gtfs_id.eq_ignore_ascii_case(query)
+                || stop.code.as_deref().is_some_and(|code| code.eq_ignore_ascii_case(query))
+        })
+        .filter_map(|stop| {
+            Some(Feature {
+                geometry: Geometry { coordinates: vec![stop.lon?, stop.lat?] },
+                properties: Properties {
+                    label: match &stop.code {
+                        Some(code) => format!("{} ({})", stop.name, code),
+                        None => stop.name.clone(),
+                    },
+                },
+            })
+        })
+        .collect();
+    Ok(LocationResponse { features })
+}
+
+async fn search(
+    client: &Client,
+    config: &Config,
+    focus_point: Option<(f64, f64)>,
+    language: Language,
+    query: &str,
+) -> Result<LocationResponse> {
+    if let Some(variables) = stop_variables(query) {
+        match get_stops(client, config, variables, query).await {
+            Ok(response) if !response.features.is_empty() => return Ok(response),
+            Ok(_) => tracing::debug!("No stops found for {:?}, searching by name", query),
+            Err(error) => tracing::warn!("Failed to look up stop {:?}: {:?}", query, error),
+        }
+    }
+    get_locations(client, config, focus_point, language, query).await
+}
+
+async fn get_locations(
+    client: &Client,
+    config: &Config,
+    focus_point: Option<(f64, f64)>,
+    language: Language,
+    query: &str,
+) -> Result<LocationResponse> {
+    let mut parameters = vec![
+        ("text", query.to_string()),
+        ("lang", language.code().to_string()),
+        ("size", config.location_limit.to_string()),
+    ];
+    if let Some((lat, lon)) = focus_point {
+        parameters.push(("focus.point.lat", lat.to_string()));
+        parameters.push(("focus.point.lon", lon.to_string()));
+    }
+    let request = config.authorize(client.get(&config.geocoding_url)).query(&parameters);
+    let mut response: LocationResponse =
+        send_timed(request, "geocoding").await?.error_for_status()?.json().await?;
+    response.features.retain(|feature| {
+        let valid = feature.geometry.coordinates.len() >= 2;
+        if !valid {
+            tracing::warn!("Skipping location without coordinates: {:?}", feature);
+        }
+        valid
+    });
+    for feature in &mut response.features {
+        if feature.properties.label.trim().is_empty() {
+            if let [lon, lat, ..] = feature.geometry.coordinates[..] {
+                feature.properties.label = format!("{:.5}, {:.5}", lat, lon);
             }
+        }
+    }
+    Ok(response)
+}
+
+pub async fn geocode(client: &Client, config: &Config, query: &str) -> Result<Feature> {
+    search(client, config, config.focus_point, config.language, query)
+        .await?
+        .features
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No locations found for {:?}", query))
+}
+
+#[derive(Deserialize)]
+struct IpLocation {
+    latitude: f64,
+    longitude: f64,
+}
+
+pub const CURRENT_LOCATION: &str = "Current location";
+const IP_LOCATION_URL: &str = "https://ipapi.co/json/";
+
+async fn current_location(client: &Client, config: &Config) -> Result<Feature> {
+    let (lat, lon) = match config.current_location {
+        Some(coordinates) => coordinates,
+        // Looking up the IP address shares it with a third party, so only do it when asked to
+        None if config.ip_location => {
+            let request = client.get(IP_LOCATION_URL);
+            let location: IpLocation =
+                send_timed(request, "ip location").await?.error_for_status()?.json().await?;
+            (location.latitude, location.longitude)
+        }
+        None => {
+            return Err(anyhow!(
+                "Set RSHSL_CURRENT_LOCATION or RSHSL_IP_LOCATION to use the current location"
+            ))
+        }
+    };
+    Ok(Feature {
+        geometry: Geometry { coordinates: vec![lon, lat] },
+        properties: Properties { label: CURRENT_LOCATION.to_string() },
+    })
+}
+
+fn initial_locations() -> Vec<Feature> {
+    let favorites: Vec<Feature> = persistence::load_favorites()
+        .into_iter()
+        .map(|favorite| Feature {
+            geometry: favorite.feature.geometry,
+            properties: Properties { label: favorite.name },
         })
+        .collect();
+    let history = persistence::load_history().into_iter().filter(|entry| {
+        !favorites.iter().any(|favorite| favorite.properties.label == entry.properties.label)
+    });
+    favorites.iter().cloned().chain(history).collect()
+}
+
+const HELP: &[(&str, &str)] = &[
+    ("Type", "Search for a location, stop code or stop ID"),
+    ("Tab", "Switch between the search and the results"),
+    ("Up/Down, Alt+k/j", "Select location"),
+    ("Alt+g/G", "Select the first or last location"),
+    ("k/j, g/G", "Select location when the results are focused"),
+    ("Left/Right/Home/End", "Move the cursor"),
+    ("Enter", "Confirm location"),
+    ("?", "Show this help when the search is empty"),
+    ("Esc", "Quit"),
+    ("q", "Quit when the results are focused"),
+];
+
+const SHORTCUT_HELP: &[(&[LocationAction], &str)] = &[
+    (&[LocationAction::CurrentLocation], "Use the current location"),
+    (&[LocationAction::Favorites], "Show favorites and recent locations"),
+    (&[LocationAction::SaveFavorite], "Save the selected location as a favorite"),
+    (
+        &[LocationAction::AddVia],
+        "Add the selected location as a via point when choosing the destination",
+    ),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Input,
+    Results,
+}
+
+fn focus_style(focused: bool) -> Style {
+    if focused {
+        Style::default().fg(Color::Cyan)
+    } else {
+        Style::default()
+    }
+}
+
+const LOCATION_HEIGHT: usize = 2;
+const PLACEHOLDER: &str = "Start typing a stop or address\u{2026}";
+
+fn distance((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let (dlat, dlon) = (lat2 - lat1, (lon2 - lon1).to_radians());
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * a.sqrt().asin()
+}
+
+fn location_details(feature: &Feature, focus_point: Option<(f64, f64)>) -> String {
+    let [lon, lat, ..] = feature.geometry.coordinates[..] else {
+        return String::new();
     };
+    match focus_point {
+        Some(focus_point) => format!(
+            "{:.5}, {:.5} \u{00B7} {}",
+            lat,
+            lon,
+            format_distance(distance(focus_point, (lat, lon)))
+        ),
+        None => format!("{:.5}, {:.5}", lat, lon),
+    }
+}
+
+fn match_range(label: &str, query: &str) -> Option<(usize, usize)> {
+    let query: Vec<char> = query.trim().chars().flat_map(char::to_lowercase).collect();
+    if query.is_empty() {
+        return None;
+    }
+    label.char_indices().find_map(|(start, _)| {
+        let mut remaining = query.iter().peekable();
+        for (offset, c) in label[start..].char_indices() {
+            if remaining.peek().is_none() {
+                return Some((start, start + offset));
+            }
+            for lower in c.to_lowercase() {
+                if remaining.next() != Some(&lower) {
+                    return None;
+                }
+            }
+        }
+        remaining.peek().is_none().then_some((start, label.len()))
+    })
+}
+
+fn highlight_match(label: &str, query: &str) -> Line<'static> {
+    match match_range(label, query) {
+        Some((start, end)) => Line::from(vec![
+            Span::raw(label[..start].to_string()),
+            Span::styled(
+                label[start..end].to_string(),
+                Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED),
+            ),
+            Span::raw(label[end..].to_string()),
+        ]),
+        None => Line::from(label.to_string()),
+    }
+}
+
+const DEBOUNCE: Duration = Duration::from_millis(300);
+const RATE_LIMITED_DEBOUNCE: Duration = Duration::from_secs(2);
+const CACHE_LIMIT: usize = 50;
+
+#[derive(Clone, Copy)]
+enum Movement {
+    Up,
+    Down,
+}
+
+fn next_index(current: Option<usize>, len: usize, movement: Movement) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    Some(match (current, movement) {
+        (None, _) => 0,
+        (Some(0), Movement::Up) => len - 1,
+        (Some(i), Movement::Up) => (i - 1).min(len - 1),
+        (Some(i), Movement::Down) if i + 1 >= len => 0,
+        (Some(i), Movement::Down) => i + 1,
+    })
+}
+
+fn byte_index(input: &str, cursor: usize) -> usize {
+    input.char_indices().nth(cursor).map_or(input.len(), |(index, _)| index)
+}
+
+// Searches for locations in the background, keeping its cache between the location prompts
+pub struct LocationSearch {
+    input: Arc<RwLock<String>>,
+    focus_point: Arc<RwLock<Option<(f64, f64)>>>,
+    locations: Arc<RwLock<LocationResponse>>,
+    input_notify: Arc<Notify>,
+    input_sequence: Arc<AtomicUsize>,
+    fetching: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,
+    rate_limited: Arc<AtomicBool>,
+    cancel: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+impl LocationSearch {
+    pub fn spawn(
+        client: &Client,
+        config: &Config,
+        language: Arc<RwLock<Language>>,
+    ) -> LocationSearch {
+        let input = Arc::new(RwLock::new(String::new()));
+        let focus_point = Arc::new(RwLock::new(config.focus_point));
+        let locations = Arc::new(RwLock::new(LocationResponse { features: initial_locations() }));
+
+        let input_notify = Arc::new(Notify::new());
+        let input_sequence = Arc::new(AtomicUsize::new(0));
+        let fetching = Arc::new(AtomicBool::new(false));
+        let failed = Arc::new(AtomicBool::new(false));
+        let rate_limited = Arc::new(AtomicBool::new(false));
+
+        let cancel = CancellationToken::new();
+        let task = {
+            let cancel = cancel.clone();
+            let input = input.clone();
+            let locations = locations.clone();
+            let input_notify = input_notify.clone();
+            let input_sequence = input_sequence.clone();
+            let fetching = fetching.clone();
+            let failed = failed.clone();
+            let rate_limited = rate_limited.clone();
+            let client = client.clone();
+            let config = config.clone();
+            let focus_point = focus_point.clone();
+            tokio::spawn(async move {
+                let mut cache: HashMap<String, LocationResponse> = HashMap::new();
+                let mut cache_order: VecDeque<String> = VecDeque::new();
+                let mut pending = false;
+                let mut debounce = DEBOUNCE;
+                'task: loop {
+                    if !pending {
+                        tokio::select! {
+                            _ = input_notify.notified() => (),
+                            _ = cancel.cancelled() => break 'task,
+                        }
+                    }
+                    pending = false;
+                    fetching.store(true, Relaxed);
+
+                    // Wait until the input has settled before querying
+                    loop {
+                        tokio::select! {
+                            _ = tokio::time::sleep(debounce) => break,
+                            _ = input_notify.notified() => (),
+                            _ = cancel.cancelled() => break 'task,
+                        }
+                    }
+
+                    let (input, sequence) = {
+                        let input = input.read().await;
+                        (input.clone(), input_sequence.load(Relaxed))
+                    };
+                    if input.is_empty() {
+                        *locations.write().await =
+                            LocationResponse { features: initial_locations() };
+                        failed.store(false, Relaxed);
+                        fetching.store(false, Relaxed);
+                        continue;
+                    }
+                    let focus_point = *focus_point.read().await;
+                    let language = *language.read().await;
+                    let key = format!("{} @ {:?} {}", input.trim(), focus_point, language.code());
+                    if let Some(cached) = cache.get(&key) {
+                        tracing::debug!("Using cached locations for {:?}", key);
+                        *locations.write().await = cached.clone();
+                        cache_order.retain(|entry| entry != &key);
+                        cache_order.push_back(key);
+                        failed.store(false, Relaxed);
+                        fetching.store(false, Relaxed);
+                        continue;
+                    }
+                    let result = tokio::select! {
+                        result = search(&client, &config, focus_point, language, &input) => result,
+                        _ = input_notify.notified() => {
+                            pending = true;
+                            continue;
+                        }
+                        _ = cancel.cancelled() => break 'task,
+                    };
+                    if input_sequence.load(Relaxed) != sequence {
+                        tracing::debug!("Discarding stale locations for {:?}", input);
+                        fetching.store(false, Relaxed);
+                        continue;
+                    }
+                    match result {
+                        Ok(result) => {
+                            tracing::info!("{:?}", result);
+                            if cache_order.len() >= CACHE_LIMIT {
+                                if let Some(oldest) = cache_order.pop_front() {
+                                    cache.remove(&oldest);
+                                }
+                            }
+                            cache.insert(key.clone(), result.clone());
+                            cache_order.push_back(key);
+                            *locations.write().await = result;
+                            failed.store(false, Relaxed);
+                            rate_limited.store(false, Relaxed);
+                            debounce = DEBOUNCE;
+                        }
+                        Err(error) => {
+                            tracing::warn!("Failed to fetch locations: {:?}", error);
+                            *locations.write().await = LocationResponse { features: vec![] };
+                            failed.store(true, Relaxed);
+                            if is_rate_limited(&error) {
+                                rate_limited.store(true, Relaxed);
+                                debounce = RATE_LIMITED_DEBOUNCE;
+                            }
+                        }
+                    }
+                    fetching.store(false, Relaxed);
+                }
+            })
+        };
+
+        LocationSearch {
+            input,
+            focus_point,
+            locations,
+            input_notify,
+            input_sequence,
+            fetching,
+            failed,
+            rate_limited,
+            cancel,
+            task,
+        }
+    }
+
+    async fn reset(&self, focus_point: Option<(f64, f64)>) {
+        *self.focus_point.write().await = focus_point;
+        self.input.write().await.clear();
+        *self.locations.write().await = LocationResponse { features: initial_locations() };
+        self.failed.store(false, Relaxed);
+        // Makes any search still running for the previous prompt stale
+        self.input_sequence.fetch_add(1, Relaxed);
+    }
+
+    pub async fn stop(self) -> Result<()> {
+        self.cancel.cancel();
+        self.task.await?;
+        Ok(())
+    }
+}
+
+pub async fn get_location<B: Backend>(
+    terminal: &mut Terminal<B>,
+    client: &Client,
+    config: &Config,
+    search: &LocationSearch,
+    focus: Option<&Feature>,
+    mut via: Option<&mut Vec<Feature>>,
+) -> Result<Option<Feature>> {
+    let focus_point = focus
+        .and_then(|feature| match feature.geometry.coordinates[..] {
+            [lon, lat, ..] => Some((lat, lon)),
+            _ => None,
+        })
+        .or(config.focus_point);
+    search.reset(focus_point).await;
+    let LocationSearch {
+        input,
+        locations,
+        input_notify,
+        input_sequence,
+        fetching,
+        failed,
+        rate_limited,
+        ..
+    } = search;
 
     let mut locations_state = ListState::default();
+    let mut cursor: usize = 0;
+    // Only set while the results list is drawn, so clicks on a message select nothing
+    let mut results_area: Option<Rect> = None;
+    let mut naming: Option<(Feature, String)> = None;
+    let keymap = &config.keymap.location;
+    let search_on_enter = config.search == SearchMode::Enter;
+    let mut searched = 0;
+    let title = format!(
+        "Location ({}: current location, {}: favorites, {}: save)",
+        keymap.keys(LocationAction::CurrentLocation),
+        keymap.keys(LocationAction::Favorites),
+        keymap.keys(LocationAction::SaveFavorite)
+    );
+    let help: Vec<_> = HELP
+        .iter()
+        .map(|(key, description)| (key.to_string(), *description))
+        .chain(keymap.help(SHORTCUT_HELP))
+        .collect();
+    let mut show_help = false;
+    let mut focus = Focus::Input;
+    let mut tick: usize = 0;
+    let mut locating: Option<JoinHandle<Result<Feature>>> = None;
+    let mut locate_error: Option<String> = None;
 
-    loop {
+    let location = loop {
+        tick = tick.wrapping_add(1);
+        if let Some(task) = locating.take_if(|task| task.is_finished()) {
+            match task.await? {
+                Ok(location) => break Some(location),
+                Err(error) => {
+                    tracing::warn!("Failed to determine current location: {:?}", error);
+                    locate_error = Some(format!("Current location unavailable: {}", error));
+                }
+            }
+        }
         {
             let input = input.read().await.clone();
             let locations = locations.read().await.clone();
+            if locations_state.selected().is_some_and(|i| i >= locations.features.len()) {
+                locations_state.select(None);
+            }
+            let message = if locating.is_some() {
+                Some(format!("{} Locating...", ui::spinner(tick)))
+            } else if let Some(error) = &locate_error {
+                Some(error.clone())
+            } else if fetching.load(Relaxed) {
+                Some(format!("{} Searching...", ui::spinner(tick)))
+            } else if rate_limited.load(Relaxed) && failed.load(Relaxed) {
+                Some("Rate limited, slowing down".to_string())
+            } else if search_on_enter
+                && !input.is_empty()
+                && searched != input_sequence.load(Relaxed)
+            {
+                Some("Press Enter to search".to_string())
+            } else if failed.load(Relaxed) && search_on_enter {
+                Some("Search failed, press Enter to retry".to_string())
+            } else if failed.load(Relaxed) {
+                Some("Search failed, keep typing to retry".to_string())
+            } else if !locations.features.is_empty() {
+                None
+            } else if input.is_empty() {
+                Some("Type to search nearby stops and addresses".to_string())
+            } else {
+                Some("No matches".to_string())
+            };
+            let via_names = via.as_ref().filter(|via| !via.is_empty()).map(|via| {
+                let names: Vec<_> =
+                    via.iter().map(|feature| feature.properties.label.as_str()).collect();
+                format!("Via {}", names.join(", "))
+            });
             terminal.draw(|frame| {
+                results_area = None;
+                if ui::too_small(frame) {
+                    return;
+                }
                 let chunks = Layout::default()
                     .direction(Direction::Vertical)
                     .constraints([Constraint::Length(3), Constraint::Min(0)])
                     .margin(1)
                     .split(frame.size());
 
-                let input_block = Paragraph::new(input.clone())
-                    .block(Block::default().title("Location").borders(Borders::ALL));
-                frame.set_cursor(chunks[0].x + input.width() as u16 + 1, chunks[0].y + 1);
-                frame.render_widget(input_block, chunks[0]);
-
-                let items: Vec<ListItem> = locations
-                    .features
-                    .iter()
-                    .map(|feature| ListItem::new(feature.properties.label.clone()))
-                    .collect();
-                let results_block = List::new(items)
-                    .highlight_style(Style::default().fg(Color::Black).bg(Color::White))
-                    .block(Block::default().title("Locations").borders(Borders::ALL));
-                frame.render_stateful_widget(results_block, chunks[1], &mut locations_state);
+                if let Some((_, name)) = &naming {
+                    let name_block = Paragraph::new(name.clone()).block(
+                        Block::default()
+                            .title("Favorite name (Enter: save, Esc: cancel)")
+                            .borders(Borders::ALL),
+                    );
+                    frame.set_cursor(chunks[0].x + name.width() as u16 + 1, chunks[0].y + 1);
+                    frame.render_widget(name_block, chunks[0]);
+                } else {
+                    let mut input_block = Block::default()
+                        .title(title.as_str())
+                        .borders(Borders::ALL)
+                        .border_style(focus_style(focus == Focus::Input));
+                    if let Some(via_names) = &via_names {
+                        input_block = input_block
+                            .title(Title::from(via_names.clone()).position(Position::Bottom));
+                    }
+                    let input_block = if input.is_empty() {
+                        Paragraph::new(Span::styled(
+                            PLACEHOLDER,
+                            Style::default().add_modifier(Modifier::DIM),
+                        ))
+                    } else {
+                        input_block = input_block.title(
+                            Title::from(format!("{} chars", input.chars().count()))
+                                .alignment(Alignment::Right)
+                                .position(Position::Bottom),
+                        );
+                        Paragraph::new(input.clone())
+                    }
+                    .block(input_block);
+                    if focus == Focus::Input {
+                        frame.set_cursor(
+                            chunks[0].x + input[..byte_index(&input, cursor)].width() as u16 + 1,
+                            chunks[0].y + 1,
+                        );
+                    }
+                    frame.render_widget(input_block, chunks[0]);
+                }
+
+                let block = Block::default()
+                    .title(if input.is_empty() { "Favorites and recent" } else { "Locations" })
+                    .borders(Borders::ALL)
+                    .border_style(focus_style(focus == Focus::Results));
+                if let Some(message) = message {
+                    let message_block = Paragraph::new(message)
+                        .style(Style::default().add_modifier(Modifier::DIM))
+                        .block(block);
+                    frame.render_widget(message_block, chunks[1]);
+                } else {
+                    results_area = Some(chunks[1]);
+                    let items: Vec<ListItem> = locations
+                        .features
+                        .iter()
+                        .map(|feature| {
+                            ListItem::new(vec![
+                                highlight_match(&feature.properties.label, &input),
+                                Line::from(Span::styled(
+                                    location_details(feature, focus_point),
+                                    Style::default().add_modifier(Modifier::DIM),
+                                )),
+                            ])
+                        })
+                        .collect();
+                    let results_block = List::new(items)
+                        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+                        .block(block);
+                    frame.render_stateful_widget(results_block, chunks[1], &mut locations_state);
+                }
+
+                if show_help {
+                    help::render_help(frame, &help);
+                }
             })?;
         }
 
         if event::poll(Duration::from_millis(16))? {
-            if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Enter => break,
-                    KeyCode::Char(c) => {
+            let event = event::read()?;
+            if let Event::Key(_) | Event::Paste(_) = event {
+                locate_error = None;
+            }
+            let code = match event {
+                Event::Key(_) if show_help => {
+                    show_help = false;
+                    None
+                }
+                Event::Key(key) if naming.is_none() && keymap.action(Key::from(key)).is_some() => {
+                    match keymap.action(Key::from(key)) {
+                        Some(LocationAction::CurrentLocation) if locating.is_none() => {
+                            let (client, config) = (client.clone(), config.clone());
+                            locating = Some(tokio::spawn(async move {
+                                current_location(&client, &config).await
+                            }));
+                        }
+                        Some(LocationAction::Favorites) => {
+                            input.write().await.clear();
+                            cursor = 0;
+                            *locations.write().await =
+                                LocationResponse { features: initial_locations() };
+                            locations_state.select(None);
+                            input_sequence.fetch_add(1, Relaxed);
+                            input_notify.notify_one();
+                        }
+                        Some(LocationAction::SaveFavorite) => {
+                            let locations = locations.read().await;
+                            naming = locations_state
+                                .selected()
+                                .and_then(|i| locations.features.get(i).cloned())
+                                .map(|feature| (feature, String::new()));
+                        }
+                        Some(LocationAction::AddVia) => {
+                            let selected = {
+                                let locations = locations.read().await;
+                                locations_state
+                                    .selected()
+                                    .and_then(|i| locations.features.get(i).cloned())
+                            };
+                            if let (Some(via), Some(feature)) = (via.as_mut(), selected) {
+                                via.push(feature);
+                                input.write().await.clear();
+                                cursor = 0;
+                                *locations.write().await =
+                                    LocationResponse { features: initial_locations() };
+                                locations_state.select(None);
+                                input_sequence.fetch_add(1, Relaxed);
+                                input_notify.notify_one();
+                            }
+                        }
+                        _ => (),
+                    }
+                    None
+                }
+                Event::Key(key)
+                    if naming.is_none() && key.modifiers.contains(KeyModifiers::ALT) =>
+                {
+                    match key.code {
+                        KeyCode::Char('j') => Some(KeyCode::Down),
+                        KeyCode::Char('k') => Some(KeyCode::Up),
+                        KeyCode::Char('g') => {
+                            if !locations.read().await.features.is_empty() {
+                                locations_state.select(Some(0));
+                            }
+                            None
+                        }
+                        KeyCode::Char('G') => {
+                            let len = locations.read().await.features.len();
+                            locations_state.select(len.checked_sub(1));
+                            None
+                        }
+                        _ => None,
+                    }
+                }
+                Event::Key(key) => Some(key.code),
+                Event::Paste(text) => {
+                    let text: String = text
+                        .chars()
+                        .map(|c| if c.is_whitespace() { ' ' } else { c })
+                        .filter(|c| !c.is_control())
+                        .collect();
+                    if let Some((_, name)) = naming.as_mut() {
+                        name.push_str(&text);
+                    } else if !text.is_empty() {
                         let mut input = input.write().await;
-                        input.push(c);
-                        input_notify.notify_one();
+                        let index = byte_index(&input, cursor);
+                        input.insert_str(index, &text);
+                        cursor += text.chars().count();
+                        focus = Focus::Input;
+                        input_sequence.fetch_add(1, Relaxed);
+                        if !search_on_enter {
+                            input_notify.notify_one();
+                        }
                     }
+                    None
+                }
+                Event::Resize(..) => {
+                    terminal.clear()?;
+                    None
+                }
+                Event::Mouse(mouse) => match mouse.kind {
+                    MouseEventKind::ScrollUp => Some(KeyCode::Up),
+                    MouseEventKind::ScrollDown => Some(KeyCode::Down),
+                    MouseEventKind::Down(MouseButton::Left) => {
+                        let inner = results_area
+                            .map(|area| area.inner(&Margin { vertical: 1, horizontal: 1 }))
+                            .filter(|inner| {
+                                inner.intersects(Rect::new(mouse.column, mouse.row, 1, 1))
+                            });
+                        if let Some(inner) = inner {
+                            let index = (mouse.row - inner.y) as usize / LOCATION_HEIGHT
+                                + locations_state.offset();
+                            if index < locations.read().await.features.len() {
+                                locations_state.select(Some(index));
+                            }
+                        }
+                        None
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            let code = match (focus, code) {
+                _ if naming.is_some() => code,
+                (_, Some(KeyCode::Tab)) => {
+                    focus = match focus {
+                        Focus::Input => Focus::Results,
+                        Focus::Results => Focus::Input,
+                    };
+                    None
+                }
+                (Focus::Results, Some(code)) => match ui::vim_key(code) {
+                    KeyCode::Home => {
+                        if !locations.read().await.features.is_empty() {
+                            locations_state.select(Some(0));
+                        }
+                        None
+                    }
+                    KeyCode::End => {
+                        let len = locations.read().await.features.len();
+                        locations_state.select(len.checked_sub(1));
+                        None
+                    }
+                    KeyCode::Char('q') => Some(KeyCode::Esc),
+                    code @ (KeyCode::Up
+                    | KeyCode::Down
+                    | KeyCode::Enter
+                    | KeyCode::Esc
+                    | KeyCode::Char('?')) => Some(code),
+                    _ => None,
+                },
+                (_, code) => code,
+            };
+            if let (Some(code), Some((feature, name))) = (code, naming.as_mut()) {
+                match code {
+                    KeyCode::Enter if !name.trim().is_empty() => {
+                        if let Err(error) = persistence::add_favorite(name.trim(), feature) {
+                            tracing::warn!("Failed to save favorite: {:?}", error);
+                        }
+                        if input.read().await.is_empty() {
+                            *locations.write().await =
+                                LocationResponse { features: initial_locations() };
+                        }
+                        naming = None;
+                    }
+                    KeyCode::Esc => naming = None,
+                    KeyCode::Char(c) => name.push(c),
                     KeyCode::Backspace => {
-                        let mut input = input.write().await;
-                        input.pop();
+                        name.pop();
+                    }
+                    _ => (),
+                }
+            } else if let Some(code) = code {
+                match code {
+                    KeyCode::Enter
+                        if search_on_enter
+                            && focus == Focus::Input
+                            && !input.read().await.is_empty()
+                            && (searched != input_sequence.load(Relaxed)
+                                || failed.load(Relaxed)) =>
+                    {
+                        searched = input_sequence.load(Relaxed);
                         input_notify.notify_one();
                     }
-                    KeyCode::Up => {
-                        let locations = locations.read().await;
-                        if !locations.features.is_empty() {
-                            let i = match locations_state.selected() {
-                                Some(i) => {
-                                    if i == 0 {
-                                        locations.features.len() - 1
-                                    } else {
-                                        i - 1
-                                    }
-                                }
-                                None => 0,
-                            };
-                            locations_state.select(Some(i));
-                        }
-                    }
-                    KeyCode::Down => {
-                        let locations = locations.read().await;
-                        if !locations.features.is_empty() {
-                            let i = match locations_state.selected() {
-                                Some(i) => {
-                                    if i >= locations.features.len() - 1 {
-                                        0
-                                    } else {
-                                        i + 1
-                                    }
-                                }
-                                None => 0,
-                            };
-                            locations_state.select(Some(i));
+                    KeyCode::Enter => {
+                        let location = {
+                            let locations = locations.read().await;
+                            locations_state
+                                .selected()
+                                .and_then(|selected| locations.features.get(selected).cloned())
+                        };
+                        if let Some(location) = location {
+                            if let Err(error) = persistence::record_history(&location) {
+                                tracing::warn!("Failed to record location history: {:?}", error);
+                            }
+                            break Some(location);
+                        }
+                    }
+                    KeyCode::Esc => break None,
+                    KeyCode::Char('?')
+                        if focus == Focus::Results || input.read().await.is_empty() =>
+                    {
+                        show_help = true
+                    }
+                    KeyCode::Char(c) => {
+                        let mut input = input.write().await;
+                        let index = byte_index(&input, cursor);
+                        input.insert(index, c);
+                        cursor += 1;
+                        input_sequence.fetch_add(1, Relaxed);
+                        if !search_on_enter {
+                            input_notify.notify_one();
                         }
                     }
+                    KeyCode::Backspace if cursor > 0 => {
+                        let mut input = input.write().await;
+                        cursor -= 1;
+                        let index = byte_index(&input, cursor);
+                        input.remove(index);
+                        input_sequence.fetch_add(1, Relaxed);
+                        // Clearing the search brings back the favorites without a request
+                        if !search_on_enter || input.is_empty() {
+                            input_notify.notify_one();
+                        }
+                    }
+                    KeyCode::Left => {
+                        cursor = cursor.saturating_sub(1);
+                    }
+                    KeyCode::Right => {
+                        cursor = (cursor + 1).min(input.read().await.chars().count());
+                    }
+                    KeyCode::Home => {
+                        cursor = 0;
+                    }
+                    KeyCode::End => {
+                        cursor = input.read().await.chars().count();
+                    }
+                    KeyCode::Up | KeyCode::Down => {
+                        let movement =
+                            if code == KeyCode::Up { Movement::Up } else { Movement::Down };
+                        let len = locations.read().await.features.len();
+                        locations_state.select(next_index(
+                            locations_state.selected(),
+                            len,
+                            movement,
+                        ));
+                    }
                     _ => (),
                 }
             }
         }
+    };
+    if let Some(task) = locating {
+        task.abort();
     }
 
-    locations_task.abort();
+    Ok(location)
+}
 
-    if let Some(selected) = locations_state.selected() {
-        let location = &locations.read().await.features[selected];
-        Ok(location.clone())
-    } else {
-        Err(anyhow!("Missing location selection"))
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::{
+        matchers::{method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::*;
+
+    fn mock_config(server: &MockServer) -> Config {
+        Config::with_endpoints(
+            format!("{}/graphql", server.uri()),
+            format!("{}/autocomplete", server.uri()),
+        )
+    }
+
+    fn feature(label: &str, coordinates: &[f64]) -> serde_json::Value {
+        json!({ "geometry": { "coordinates": coordinates }, "properties": { "label": label } })
+    }
+
+    #[tokio::test]
+    async fn get_locations_skips_features_without_coordinates() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/autocomplete"))
+            .and(query_param("text", "kamppi"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "features": [
+                    feature("Kamppi, Helsinki", &[24.9316, 60.1690]),
+                    feature("Nowhere", &[]),
+                ]
+            })))
+            .mount(&server)
+            .await;
+        let config = mock_config(&server);
+
+        let response =
+            get_locations(&Client::new(), &config, None, config.language, "kamppi").await.unwrap();
+
+        let labels: Vec<_> =
+            response.features.iter().map(|feature| feature.properties.label.as_str()).collect();
+        assert_eq!(labels, ["Kamppi, Helsinki"]);
+        assert_eq!(response.features[0].geometry.coordinates, [24.9316, 60.1690]);
+    }
+
+    #[tokio::test]
+    async fn get_locations_labels_features_without_label() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/autocomplete"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "features": [
+                    feature("Kamppi, Helsinki", &[24.9316, 60.1690]),
+                    { "geometry": { "coordinates": [24.9414, 60.1710] }, "properties": {} },
+                    { "geometry": { "coordinates": [24.9335, 60.1989] } },
+                ]
+            })))
+            .mount(&server)
+            .await;
+        let config = mock_config(&server);
+
+        let response =
+            get_locations(&Client::new(), &config, None, config.language, "kamppi").await.unwrap();
+
+        let labels: Vec<_> =
+            response.features.iter().map(|feature| feature.properties.label.as_str()).collect();
+        assert_eq!(labels, ["Kamppi, Helsinki", "60.17100, 24.94140", "60.19890, 24.93350"]);
+    }
+
+    #[tokio::test]
+    async fn geocode_fails_without_matches() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/autocomplete"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "features": [] })))
+            .mount(&server)
+            .await;
+        let config = mock_config(&server);
+
+        assert!(geocode(&Client::new(), &config, "nowhere").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn search_resolves_stop_codes() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/graphql"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": {
+                    "stops": [
+                        { "gtfsId": "HSL:1040601", "code": "H2034", "name": "Kamppi",
+                          "lat": 60.1690, "lon": 24.9316 },
+                        { "gtfsId": "HSL:1040602", "code": "H2035", "name": "Kamppi",
+                          "lat": 60.1691, "lon": 24.9317 }
+                    ]
+                }
+            })))
+            .mount(&server)
+            .await;
+        let config = mock_config(&server);
+
+        let response =
+            search(&Client::new(), &config, None, config.language, "h2034").await.unwrap();
+
+        let labels: Vec<_> =
+            response.features.iter().map(|feature| feature.properties.label.as_str()).collect();
+        assert_eq!(labels, ["Kamppi (H2034)"]);
+    }
+
+    #[tokio::test]
+    async fn get_locations_reports_rate_limiting() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/autocomplete"))
+            .respond_with(ResponseTemplate::new(429))
+            .mount(&server)
+            .await;
+        let config = mock_config(&server);
+
+        let error = get_locations(&Client::new(), &config, None, config.language, "kamppi")
+            .await
+            .unwrap_err();
+        assert!(is_rate_limited(&error));
+    }
+
+    #[test]
+    fn next_index_empty() {
+        assert_eq!(next_index(None, 0, Movement::Down), None);
+        assert_eq!(next_index(Some(2), 0, Movement::Up), None);
+    }
+
+    #[test]
+    fn next_index_initial() {
+        assert_eq!(next_index(None, 3, Movement::Down), Some(0));
+        assert_eq!(next_index(None, 3, Movement::Up), Some(0));
+    }
+
+    #[test]
+    fn next_index_moves() {
+        assert_eq!(next_index(Some(0), 3, Movement::Down), Some(1));
+        assert_eq!(next_index(Some(2), 3, Movement::Up), Some(1));
+    }
+
+    #[test]
+    fn next_index_wraps_at_start() {
+        assert_eq!(next_index(Some(0), 3, Movement::Up), Some(2));
+    }
+
+    #[test]
+    fn next_index_wraps_at_end() {
+        assert_eq!(next_index(Some(2), 3, Movement::Down), Some(0));
+        assert_eq!(next_index(Some(0), 1, Movement::Down), Some(0));
+    }
+
+    #[test]
+    fn match_range_ignores_case() {
+        assert_eq!(match_range("Rautatientori, Helsinki", "rautat"), Some((0, 6)));
+        assert_eq!(match_range("Helsinki Rautatieasema", " RAUTA "), Some((9, 14)));
+        assert_eq!(match_range("Töölöntori", "LÖN"), Some((5, 9)));
+        assert_eq!(match_range("Kamppi", "pasila"), None);
+        assert_eq!(match_range("Kamppi", ""), None);
     }
 }
@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{
+    DateTime, Duration as ChronoDuration, Local, LocalResult, Months, NaiveDateTime, TimeZone,
+    Timelike,
+};
+use crossterm::event::{self, Event, KeyCode};
+use ratatui::{
+    backend::Backend,
+    layout::{Alignment, Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph},
+    Terminal,
+};
+
+use crate::{config::Clock, ui};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+}
+
+#[derive(Clone)]
+pub struct TripTime {
+    pub time: NaiveDateTime,
+    pub arrive_by: bool,
+}
+
+const FIELDS: [Field; 5] = [Field::Year, Field::Month, Field::Day, Field::Hour, Field::Minute];
+const HELP: &str = "Left/Right: select field, Up/Down: adjust, n: now, a: leave at/arrive by, \
+                    Enter: confirm, Esc/q: quit";
+
+fn adjust(time: NaiveDateTime, field: Field, up: bool) -> NaiveDateTime {
+    let adjusted = match field {
+        Field::Year if up => time.checked_add_months(Months::new(12)),
+        Field::Year => time.checked_sub_months(Months::new(12)),
+        Field::Month if up => time.checked_add_months(Months::new(1)),
+        Field::Month => time.checked_sub_months(Months::new(1)),
+        Field::Day => Some(time + ChronoDuration::days(if up { 1 } else { -1 })),
+        Field::Hour => Some(time + ChronoDuration::hours(if up { 1 } else { -1 })),
+        Field::Minute => Some(time + ChronoDuration::minutes(if up { 1 } else { -1 })),
+    };
+    adjusted.unwrap_or(time)
+}
+
+// Times skipped by a DST change move past the gap and repeated ones resolve to the first occurrence
+fn resolve_local<Tz: TimeZone>(time: NaiveDateTime, tz: &Tz) -> Option<DateTime<Tz>> {
+    match tz.from_local_datetime(&time) {
+        LocalResult::Single(time) => Some(time),
+        LocalResult::Ambiguous(earliest, _) => Some(earliest),
+        LocalResult::None => tz.from_local_datetime(&(time + ChronoDuration::hours(1))).earliest(),
+    }
+}
+
+fn existing_local_time(time: NaiveDateTime) -> NaiveDateTime {
+    resolve_local(time, &Local).map_or(time, |time| time.naive_local())
+}
+
+pub async fn get_time<B: Backend>(
+    terminal: &mut Terminal<B>,
+    clock: Clock,
+) -> Result<Option<TripTime>> {
+    let mut time = Local::now().naive_local().with_second(0).unwrap();
+    let mut arrive_by = false;
+    let mut selected = 3;
+
+    loop {
+        terminal.draw(|frame| {
+            if ui::too_small(frame) {
+                return;
+            }
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(3), Constraint::Min(0)])
+                .margin(1)
+                .split(frame.size());
+
+            let mut spans: Vec<Span> = FIELDS
+                .iter()
+                .enumerate()
+                .flat_map(|(index, field)| {
+                    let (value, separator) = match field {
+                        Field::Year => (time.format("%Y").to_string(), "-"),
+                        Field::Month => (time.format("%m").to_string(), "-"),
+                        Field::Day => (time.format("%d").to_string(), " "),
+                        Field::Hour if clock == Clock::TwelveHour => {
+                            (time.format("%I").to_string(), ":")
+                        }
+                        Field::Hour => (time.format("%H").to_string(), ":"),
+                        Field::Minute => (time.format("%M").to_string(), ""),
+                    };
+                    let style = if index == selected {
+                        Style::default().add_modifier(Modifier::REVERSED)
+                    } else {
+                        Style::default()
+                    };
+                    [Span::styled(value, style), Span::raw(separator)]
+                })
+                .collect();
+            if clock == Clock::TwelveHour {
+                spans.push(Span::raw(time.format(" %p").to_string()));
+            }
+
+            let time_block = Paragraph::new(Line::from(spans)).alignment(Alignment::Center).block(
+                Block::default()
+                    .title(if arrive_by { "Arrival time" } else { "Departure time" })
+                    .borders(Borders::ALL),
+            );
+            frame.render_widget(time_block, chunks[0]);
+
+            let help_block = Paragraph::new(HELP).alignment(Alignment::Center);
+            frame.render_widget(help_block, chunks[1]);
+        })?;
+
+        if event::poll(Duration::from_millis(16))? {
+            match event::read()? {
+                Event::Key(key) => match key.code {
+                    KeyCode::Enter => break,
+                    KeyCode::Esc | KeyCode::Char('q') => return Ok(None),
+                    KeyCode::Left => selected = selected.saturating_sub(1),
+                    KeyCode::Right => selected = (selected + 1).min(FIELDS.len() - 1),
+                    KeyCode::Up => time = existing_local_time(adjust(time, FIELDS[selected], true)),
+                    KeyCode::Down => {
+                        time = existing_local_time(adjust(time, FIELDS[selected], false))
+                    }
+                    KeyCode::Char('n') => {
+                        time = Local::now().naive_local().with_second(0).unwrap();
+                    }
+                    KeyCode::Char('a') => arrive_by = !arrive_by,
+                    _ => (),
+                },
+                Event::Resize(..) => terminal.clear()?,
+                _ => (),
+            }
+        }
+    }
+
+    Ok(Some(TripTime { time, arrive_by }))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use chrono_tz::Europe::Helsinki;
+
+    use super::*;
+
+    fn helsinki(date: (i32, u32, u32), hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn resolve_local_picks_the_first_of_repeated_times() {
+        // 03:30 occurs twice in Helsinki on 2023-10-29, first in EEST and then in EET
+        let time = resolve_local(helsinki((2023, 10, 29), 3, 30), &Helsinki).unwrap();
+        assert_eq!(time.timestamp(), 1_698_539_400);
+    }
+
+    #[test]
+    fn resolve_local_moves_past_skipped_times() {
+        // Clocks go from 03:00 to 04:00 in Helsinki on 2023-03-26
+        let time = resolve_local(helsinki((2023, 3, 26), 3, 30), &Helsinki).unwrap();
+        assert_eq!(time.naive_local(), helsinki((2023, 3, 26), 4, 30));
+    }
+
+    #[test]
+    fn resolve_local_keeps_unambiguous_times() {
+        let time = resolve_local(helsinki((2023, 6, 1), 12, 0), &Helsinki).unwrap();
+        assert_eq!(time.naive_local(), helsinki((2023, 6, 1), 12, 0));
+    }
+}
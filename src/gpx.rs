@@ -0,0 +1,120 @@
+use std::fmt::Write;
+
+use anyhow::{anyhow, Result};
+
+pub fn decode_polyline(encoded: &str) -> Result<Vec<(f64, f64)>> {
+    let mut points = vec![];
+    let mut bytes = encoded.bytes();
+    let (mut lat, mut lon) = (0i64, 0i64);
+    'points: loop {
+        let mut deltas = [0i64; 2];
+        for delta in deltas.iter_mut() {
+            let (mut result, mut shift) = (0i64, 0);
+            loop {
+                let Some(byte) = bytes.next() else { break 'points };
+                if shift > 30 {
+                    return Err(anyhow!("Polyline value is too long"));
+                }
+                let chunk = byte as i64 - 63;
+                result |= (chunk & 0x1f) << shift;
+                shift += 5;
+                if chunk < 0x20 {
+                    break;
+                }
+            }
+            *delta = if result & 1 == 1 { !(result >> 1) } else { result >> 1 };
+        }
+        lat += deltas[0];
+        lon += deltas[1];
+        points.push((lat as f64 / 1e5, lon as f64 / 1e5));
+    }
+    Ok(points)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn write_gpx(name: &str, segments: &[Vec<(f64, f64)>]) -> String {
+    let mut gpx = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <gpx version=\"1.1\" creator=\"rshsl\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n  \
+         <trk>\n",
+    );
+    let _ = writeln!(gpx, "    <name>{}</name>", escape(name));
+    for segment in segments {
+        gpx.push_str("    <trkseg>\n");
+        for (lat, lon) in segment {
+            let _ = writeln!(gpx, "      <trkpt lat=\"{:.5}\" lon=\"{:.5}\"/>", lat, lon);
+        }
+        gpx.push_str("    </trkseg>\n");
+    }
+    gpx.push_str("  </trk>\n</gpx>\n");
+    gpx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCE: &str = "_p~iF~ps|U_ulLnnqC_mqNvxq`@";
+
+    fn encode_polyline(points: &[(f64, f64)]) -> String {
+        let mut encoded = String::new();
+        let (mut previous_lat, mut previous_lon) = (0i64, 0i64);
+        for (lat, lon) in points {
+            let (lat, lon) = ((lat * 1e5).round() as i64, (lon * 1e5).round() as i64);
+            for delta in [lat - previous_lat, lon - previous_lon] {
+                let mut value = if delta < 0 { !(delta << 1) } else { delta << 1 };
+                while value >= 0x20 {
+                    encoded.push((((value & 0x1f) | 0x20) as u8 + 63) as char);
+                    value >>= 5;
+                }
+                encoded.push((value as u8 + 63) as char);
+            }
+            (previous_lat, previous_lon) = (lat, lon);
+        }
+        encoded
+    }
+
+    #[test]
+    fn decodes_reference_polyline() {
+        assert_eq!(
+            decode_polyline(REFERENCE).unwrap(),
+            vec![(38.5, -120.2), (40.7, -120.95), (43.252, -126.453)]
+        );
+    }
+
+    #[test]
+    fn round_trips_reference_polyline() {
+        let points = decode_polyline(REFERENCE).unwrap();
+        assert_eq!(encode_polyline(&points), REFERENCE);
+        assert_eq!(decode_polyline(&encode_polyline(&points)).unwrap(), points);
+    }
+
+    #[test]
+    fn rejects_overlong_values() {
+        assert!(decode_polyline(&"~".repeat(16)).is_err());
+    }
+
+    #[test]
+    fn writes_gpx() {
+        let gpx = write_gpx("A & <B>", &[vec![(60.1, 24.9)], vec![(60.2, 24.95), (60.25, 25.0)]]);
+        assert_eq!(
+            gpx,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <gpx version=\"1.1\" creator=\"rshsl\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n\
+             \x20 <trk>\n\
+             \x20   <name>A &amp; &lt;B&gt;</name>\n\
+             \x20   <trkseg>\n\
+             \x20     <trkpt lat=\"60.10000\" lon=\"24.90000\"/>\n\
+             \x20   </trkseg>\n\
+             \x20   <trkseg>\n\
+             \x20     <trkpt lat=\"60.20000\" lon=\"24.95000\"/>\n\
+             \x20     <trkpt lat=\"60.25000\" lon=\"25.00000\"/>\n\
+             \x20   </trkseg>\n\
+             \x20 </trk>\n\
+             </gpx>\n"
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use anyhow::{bail, Result};
+use chrono::Local;
+use reqwest::Client;
+
+use crate::{config::Config, get_itinerary, get_location, get_time::TripTime};
+
+pub async fn run(client: &Client, config: &Config, from_query: &str, to_query: &str) -> Result<()> {
+    let from = get_location::geocode(client, config, from_query).await?;
+    let to = get_location::geocode(client, config, to_query).await?;
+    let trip_time = TripTime { time: Local::now().naive_local(), arrive_by: false };
+    let itineraries = get_itinerary::plan(client, config, from, vec![], to, trip_time).await?;
+    let Some(itinerary) = itineraries.first() else {
+        bail!("No routes found between {:?} and {:?}", from_query, to_query);
+    };
+    for line in get_itinerary::format_plain(itinerary, config.clock) {
+        println!("{}", line);
+    }
+    Ok(())
+}
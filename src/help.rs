@@ -0,0 +1,40 @@
+use ratatui::{
+    layout::Rect,
+    style::{Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Clear, Paragraph},
+    Frame,
+};
+use unicode_width::UnicodeWidthStr;
+
+const TITLE: &str = "Help (press any key to close)";
+
+pub fn render_help<K: AsRef<str>>(frame: &mut Frame, bindings: &[(K, &str)]) {
+    let key_width = bindings.iter().map(|(key, _)| key.as_ref().width()).max().unwrap_or(0);
+    let lines: Vec<Line> = bindings
+        .iter()
+        .map(|(key, description)| {
+            Line::from(vec![
+                Span::styled(
+                    format!("{:width$}  ", key.as_ref(), width = key_width),
+                    Style::default().add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(*description),
+            ])
+        })
+        .collect();
+    let width = lines.iter().map(Line::width).chain([TITLE.width()]).max().unwrap_or(0) as u16 + 4;
+    let height = lines.len() as u16 + 2;
+
+    let size = frame.size();
+    let area = Rect::new(
+        size.x + size.width.saturating_sub(width) / 2,
+        size.y + size.height.saturating_sub(height) / 2,
+        width.min(size.width),
+        height.min(size.height),
+    );
+    let help_block =
+        Paragraph::new(lines).block(Block::default().title(TITLE).borders(Borders::ALL));
+    frame.render_widget(Clear, area);
+    frame.render_widget(help_block, area);
+}
@@ -0,0 +1,47 @@
+use std::time::Instant;
+
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use tracing::Instrument;
+
+use crate::config::Config;
+
+pub fn client(config: &Config) -> reqwest::Result<Client> {
+    Client::builder()
+        .timeout(config.request_timeout)
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+pub fn is_rate_limited(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        .is_some_and(|status| status == StatusCode::TOO_MANY_REQUESTS)
+}
+
+pub async fn send_timed(request: RequestBuilder, name: &str) -> reqwest::Result<Response> {
+    let start = Instant::now();
+    let result = request.send().instrument(tracing::info_span!("request", name)).await;
+    let elapsed = start.elapsed();
+    let elapsed_ms = elapsed.as_millis() as u64;
+    match &result {
+        Ok(response) => tracing::info!(
+            name,
+            elapsed_ms,
+            status = response.status().as_u16(),
+            "{} request took {:.1?}, {}",
+            name,
+            elapsed,
+            response.status()
+        ),
+        Err(error) => tracing::warn!(
+            name,
+            elapsed_ms,
+            "{} request failed after {:.1?}: {}",
+            name,
+            elapsed,
+            error
+        ),
+    }
+    result
+}
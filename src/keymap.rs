@@ -0,0 +1,333 @@
+use std::{collections::HashMap, fmt, fs, str::FromStr};
+
+use anyhow::{anyhow, bail, Error};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+
+use crate::config::config_dir;
+
+const KEYMAP_FILE: &str = "keymap.toml";
+
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("Esc", KeyCode::Esc),
+    ("Enter", KeyCode::Enter),
+    ("Tab", KeyCode::Tab),
+    ("Backspace", KeyCode::Backspace),
+    ("Delete", KeyCode::Delete),
+    ("Insert", KeyCode::Insert),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Space", KeyCode::Char(' ')),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub code: KeyCode,
+    pub ctrl: bool,
+}
+
+impl From<KeyEvent> for Key {
+    fn from(event: KeyEvent) -> Key {
+        Key { code: event.code, ctrl: event.modifiers.contains(KeyModifiers::CONTROL) }
+    }
+}
+
+impl FromStr for Key {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Key, Error> {
+        let (ctrl, name) = match s.split_once('+') {
+            Some((modifier, name)) if modifier.eq_ignore_ascii_case("ctrl") && !name.is_empty() => {
+                (true, name)
+            }
+            _ => (false, s),
+        };
+        let mut chars = name.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(if ctrl { c.to_ascii_lowercase() } else { c }),
+            _ => match KEY_NAMES.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)) {
+                Some((_, code)) => *code,
+                None => match name.strip_prefix(['F', 'f']).and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..=12) => KeyCode::F(n),
+                    _ => bail!("Unknown key {:?}", s),
+                },
+            },
+        };
+        Ok(Key { code, ctrl })
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        match (KEY_NAMES.iter().find(|(_, code)| *code == self.code), self.code) {
+            (Some((name, _)), _) => write!(f, "{}", name),
+            (None, KeyCode::Char(c)) if self.ctrl => write!(f, "{}", c.to_ascii_uppercase()),
+            (None, KeyCode::Char(c)) => write!(f, "{}", c),
+            (None, KeyCode::F(n)) => write!(f, "F{}", n),
+            (None, code) => write!(f, "{:?}", code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItineraryAction {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    First,
+    Last,
+    Details,
+    Close,
+    Refresh,
+    Swap,
+    Later,
+    Earlier,
+    Back,
+    Stops,
+    Compact,
+    Language,
+    More,
+    Fewer,
+    Wheelchair,
+    LessWalking,
+    MoreWalking,
+    MaxTransfers,
+    Preference,
+    ShorterTransfers,
+    LongerTransfers,
+    CityBikes,
+    ToggleMode(usize),
+    ExportJson,
+    Copy,
+    ExportGpx,
+    Map,
+    Help,
+    Quit,
+}
+
+const ITINERARY_DEFAULTS: &[(&str, ItineraryAction, &[&str])] = &[
+    ("up", ItineraryAction::Up, &["Up", "k"]),
+    ("down", ItineraryAction::Down, &["Down", "j"]),
+    ("page_up", ItineraryAction::PageUp, &["PageUp"]),
+    ("page_down", ItineraryAction::PageDown, &["PageDown"]),
+    ("first", ItineraryAction::First, &["Home", "g"]),
+    ("last", ItineraryAction::Last, &["End", "G"]),
+    ("details", ItineraryAction::Details, &["Enter"]),
+    ("close", ItineraryAction::Close, &["Esc"]),
+    ("refresh", ItineraryAction::Refresh, &["r"]),
+    ("swap", ItineraryAction::Swap, &["s"]),
+    ("later", ItineraryAction::Later, &["n"]),
+    ("earlier", ItineraryAction::Earlier, &["p"]),
+    ("back", ItineraryAction::Back, &["b"]),
+    ("stops", ItineraryAction::Stops, &["i"]),
+    ("compact", ItineraryAction::Compact, &["v"]),
+    ("language", ItineraryAction::Language, &["l"]),
+    ("more", ItineraryAction::More, &["+"]),
+    ("fewer", ItineraryAction::Fewer, &["-"]),
+    ("wheelchair", ItineraryAction::Wheelchair, &["w"]),
+    ("less_walking", ItineraryAction::LessWalking, &["["]),
+    ("more_walking", ItineraryAction::MoreWalking, &["]"]),
+    ("max_transfers", ItineraryAction::MaxTransfers, &["t"]),
+    ("preference", ItineraryAction::Preference, &["o"]),
+    ("shorter_transfers", ItineraryAction::ShorterTransfers, &["<"]),
+    ("longer_transfers", ItineraryAction::LongerTransfers, &[">"]),
+    ("city_bikes", ItineraryAction::CityBikes, &["c"]),
+    ("bus", ItineraryAction::ToggleMode(0), &["1"]),
+    ("tram", ItineraryAction::ToggleMode(1), &["2"]),
+    ("rail", ItineraryAction::ToggleMode(2), &["3"]),
+    ("subway", ItineraryAction::ToggleMode(3), &["4"]),
+    ("ferry", ItineraryAction::ToggleMode(4), &["5"]),
+    ("export_json", ItineraryAction::ExportJson, &["e"]),
+    ("copy", ItineraryAction::Copy, &["y"]),
+    ("export_gpx", ItineraryAction::ExportGpx, &["x"]),
+    ("map", ItineraryAction::Map, &["m"]),
+    ("help", ItineraryAction::Help, &["?"]),
+    ("quit", ItineraryAction::Quit, &["q"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocationAction {
+    CurrentLocation,
+    Favorites,
+    SaveFavorite,
+    AddVia,
+}
+
+const LOCATION_DEFAULTS: &[(&str, LocationAction, &[&str])] = &[
+    ("current_location", LocationAction::CurrentLocation, &["ctrl+l"]),
+    ("favorites", LocationAction::Favorites, &["ctrl+f"]),
+    ("save_favorite", LocationAction::SaveFavorite, &["ctrl+s"]),
+    ("add_via", LocationAction::AddVia, &["ctrl+v"]),
+];
+
+#[derive(Debug, Clone)]
+pub struct Bindings<A> {
+    bindings: Vec<(&'static str, A, Vec<Key>)>,
+}
+
+impl<A: Copy + PartialEq> Bindings<A> {
+    fn new(defaults: &[(&'static str, A, &[&str])]) -> Bindings<A> {
+        let bindings = defaults
+            .iter()
+            .map(|(name, action, keys)| {
+                let keys = keys.iter().map(|key| key.parse().expect("valid default key"));
+                (*name, *action, keys.collect())
+            })
+            .collect();
+        Bindings { bindings }
+    }
+
+    fn bind(&mut self, name: &str, keys: Vec<Key>) -> Result<(), Error> {
+        let index = self
+            .bindings
+            .iter()
+            .position(|(bound, ..)| *bound == name)
+            .ok_or_else(|| anyhow!("Unknown action {:?}", name))?;
+        for (_, _, bound) in &mut self.bindings {
+            bound.retain(|key| !keys.contains(key));
+        }
+        self.bindings[index].2 = keys;
+        Ok(())
+    }
+
+    pub fn action(&self, key: Key) -> Option<A> {
+        self.bindings.iter().find(|(_, _, keys)| keys.contains(&key)).map(|(_, action, _)| *action)
+    }
+
+    pub fn keys(&self, action: A) -> String {
+        let keys = self.bindings.iter().filter(|(_, bound, _)| *bound == action);
+        keys.flat_map(|(_, _, keys)| keys).map(Key::to_string).collect::<Vec<_>>().join("/")
+    }
+
+    pub fn help(&self, entries: &[(&[A], &'static str)]) -> Vec<(String, &'static str)> {
+        entries
+            .iter()
+            .map(|(actions, description)| {
+                let keys = actions.iter().map(|action| self.keys(*action));
+                let keys = keys.filter(|keys| !keys.is_empty()).collect::<Vec<_>>();
+                (keys.join(", "), *description)
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyList {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct KeymapFile {
+    itinerary: HashMap<String, KeyList>,
+    location: HashMap<String, KeyList>,
+}
+
+fn apply<A: Copy + PartialEq>(bindings: &mut Bindings<A>, overrides: HashMap<String, KeyList>) {
+    for (name, keys) in overrides {
+        let keys = match keys {
+            KeyList::One(key) => vec![key],
+            KeyList::Many(keys) => keys,
+        };
+        let result = keys
+            .iter()
+            .map(|key| key.parse())
+            .collect::<Result<Vec<Key>, _>>()
+            .and_then(|keys| bindings.bind(&name, keys));
+        if let Err(error) = result {
+            tracing::warn!("Ignoring key binding for {:?}: {}", name, error);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    pub itinerary: Bindings<ItineraryAction>,
+    pub location: Bindings<LocationAction>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap {
+            itinerary: Bindings::new(ITINERARY_DEFAULTS),
+            location: Bindings::new(LOCATION_DEFAULTS),
+        }
+    }
+}
+
+impl Keymap {
+    fn parse(contents: &str) -> Result<Keymap, toml::de::Error> {
+        let file: KeymapFile = toml::from_str(contents)?;
+        let mut keymap = Keymap::default();
+        apply(&mut keymap.itinerary, file.itinerary);
+        apply(&mut keymap.location, file.location);
+        Ok(keymap)
+    }
+
+    pub fn load() -> Keymap {
+        let Some(path) = config_dir().map(|dir| dir.join(KEYMAP_FILE)) else {
+            return Keymap::default();
+        };
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return Keymap::default();
+        };
+        Keymap::parse(&contents).unwrap_or_else(|error| {
+            tracing::warn!("Ignoring invalid keymap {}: {}", path.display(), error);
+            Keymap::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plain(code: KeyCode) -> Key {
+        Key { code, ctrl: false }
+    }
+
+    #[test]
+    fn parses_key_names() {
+        assert_eq!("q".parse::<Key>().unwrap(), plain(KeyCode::Char('q')));
+        assert_eq!("+".parse::<Key>().unwrap(), plain(KeyCode::Char('+')));
+        assert_eq!("pageup".parse::<Key>().unwrap(), plain(KeyCode::PageUp));
+        assert_eq!("F5".parse::<Key>().unwrap(), plain(KeyCode::F(5)));
+        assert_eq!("Ctrl+L".parse::<Key>().unwrap(), Key { code: KeyCode::Char('l'), ctrl: true });
+        assert!("Hyper".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn formats_keys() {
+        assert_eq!(plain(KeyCode::PageDown).to_string(), "PageDown");
+        assert_eq!(Key { code: KeyCode::Char('v'), ctrl: true }.to_string(), "Ctrl+V");
+    }
+
+    #[test]
+    fn overrides_replace_default_keys() {
+        let keymap = Keymap::parse("[itinerary]\nswap = \"S\"\nstops = [\"s\", \"F2\"]\n").unwrap();
+        let bindings = &keymap.itinerary;
+        assert_eq!(bindings.action(plain(KeyCode::Char('S'))), Some(ItineraryAction::Swap));
+        assert_eq!(bindings.action(plain(KeyCode::Char('s'))), Some(ItineraryAction::Stops));
+        assert_eq!(bindings.action(plain(KeyCode::Char('i'))), None);
+        assert_eq!(bindings.keys(ItineraryAction::Stops), "s/F2");
+    }
+
+    #[test]
+    fn ignores_unknown_actions() {
+        let keymap = Keymap::parse("[itinerary]\nteleport = \"T\"\n").unwrap();
+        assert_eq!(keymap.itinerary.action(plain(KeyCode::Char('q'))), Some(ItineraryAction::Quit));
+        assert!(Keymap::parse("[unknown]\n").is_err());
+    }
+}
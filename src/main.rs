@@ -1,18 +1,47 @@
-use std::io;
+use std::{env, io, panic, sync::Arc};
 
 use crossterm::{
+    event::{DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 
 use graphql_client::GraphQLQuery;
-use ratatui::{backend::CrosstermBackend, Terminal};
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    Terminal, TerminalOptions, Viewport,
+};
+use reqwest::Client;
+use tokio::sync::RwLock;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
 use anyhow::Result;
+use args::Args;
+use clap::Parser;
+use config::{Config, Language};
+use get_itinerary::Exit;
+use get_location::LocationSearch;
+use monochrome::Monochrome;
+use persistence::Trip;
 
+mod args;
+mod config;
 mod get_itinerary;
 mod get_location;
+mod get_time;
+mod gpx;
+mod headless;
+mod help;
+mod http;
+mod keymap;
+mod monochrome;
+mod persistence;
+mod theme;
+mod ui;
+
+const INLINE_HEIGHT: u16 = 24;
+const LOG_FILE: &str = "rshsl.log";
+const DEFAULT_LOG_LEVEL: &str = "warn";
 
 #[derive(GraphQLQuery)]
 #[graphql(
@@ -22,28 +51,130 @@ mod get_location;
 )]
 pub struct RoutesQuery;
 
+fn restore_terminal(inline: bool) -> io::Result<()> {
+    disable_raw_mode()?;
+    let mut stdout = io::stdout();
+    // Inline mode never entered the alternate screen, and leaving it would move the cursor
+    if !inline {
+        execute!(stdout, LeaveAlternateScreen)?;
+    }
+    execute!(stdout, DisableMouseCapture, DisableBracketedPaste)
+}
+
+async fn run<B: Backend>(
+    terminal: &mut Terminal<B>,
+    client: &Client,
+    config: &Config,
+    language: &RwLock<Language>,
+    search: &LocationSearch,
+) -> Result<()> {
+    let mut last_trip = persistence::load_last_trip();
+    loop {
+        let trip = match last_trip.take() {
+            Some(trip) if ui::confirm(terminal, &format!("Plan {} again?", trip.name()))? => trip,
+            _ => {
+                let Some(from) =
+                    get_location::get_location(terminal, client, config, search, None, None)
+                        .await?
+                else {
+                    return Ok(());
+                };
+                let mut via = vec![];
+                let Some(to) = get_location::get_location(
+                    terminal,
+                    client,
+                    config,
+                    search,
+                    Some(&from),
+                    Some(&mut via),
+                )
+                .await?
+                else {
+                    return Ok(());
+                };
+                Trip { from, via, to }
+            }
+        };
+        if !trip.uses_current_location() {
+            if let Err(error) = persistence::save_last_trip(&trip) {
+                tracing::warn!("Failed to save the last trip: {:?}", error);
+            }
+        }
+
+        let Some(time) = get_time::get_time(terminal, config.clock).await? else {
+            return Ok(());
+        };
+
+        match get_itinerary::get_itinerary(terminal, client, config, language, trip, time).await? {
+            Exit::Quit => return Ok(()),
+            Exit::Back => continue,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let file_appender = tracing_appender::rolling::never(".", "client.log");
+    let args = Args::parse();
+
+    let file_appender = tracing_appender::rolling::daily(config::log_dir(), LOG_FILE);
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
+    let filter = args
+        .log_level
+        .clone()
+        .or_else(|| env::var("RSHSL_LOG").ok())
+        .or_else(|| env::var(EnvFilter::DEFAULT_ENV).ok())
+        .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string());
+    let (filter, invalid_filter) = match EnvFilter::try_new(&filter) {
+        Ok(filter) => (filter, None),
+        Err(error) => (EnvFilter::new(DEFAULT_LOG_LEVEL), Some((filter, error))),
+    };
     tracing_subscriber::registry()
-        .with(fmt::layer().with_writer(non_blocking))
-        .with(EnvFilter::from_default_env())
+        .with(fmt::layer().with_writer(non_blocking).with_ansi(false))
+        .with(filter)
         .init();
+    if let Some((filter, error)) = invalid_filter {
+        tracing::warn!("Ignoring invalid log filter {:?}: {}", filter, error);
+    }
+    let config = Config::from_env()?;
+    let client = http::client(&config)?;
+
+    if let Some((from, to)) = args.trip() {
+        return headless::run(&client, &config, from, to).await;
+    }
+
+    let inline = args.inline;
+    let panic_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let _ = restore_terminal(inline);
+        panic_hook(info);
+    }));
 
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
-    let from = get_location::get_location(&mut terminal).await?;
-    let to = get_location::get_location(&mut terminal).await?;
+    let viewport = if args.inline {
+        execute!(stdout, EnableMouseCapture, EnableBracketedPaste)?;
+        Viewport::Inline(INLINE_HEIGHT)
+    } else {
+        execute!(stdout, EnterAlternateScreen, EnableMouseCapture, EnableBracketedPaste)?;
+        Viewport::Fullscreen
+    };
+    // See https://no-color.org
+    let no_color = args.no_color || env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let backend = Monochrome::new(CrosstermBackend::new(stdout), no_color);
+    let mut terminal = Terminal::with_options(backend, TerminalOptions { viewport })?;
 
-    get_itinerary::get_itinerary(&mut terminal, from, to).await?;
+    let language = Arc::new(RwLock::new(config.language));
+    let search = LocationSearch::spawn(&client, &config, language.clone());
+    let result = run(&mut terminal, &client, &config, &language, &search).await;
+    let stopped = search.stop().await;
 
-    disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    if args.inline {
+        // Leave the last frame in the scrollback and continue below it
+        let area = terminal.get_frame().size();
+        terminal.set_cursor(area.x, area.bottom().saturating_sub(1))?;
+        println!();
+    }
+    restore_terminal(args.inline)?;
 
-    Ok(())
+    result.and(stopped)
 }
@@ -0,0 +1,95 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::config_dir,
+    get_location::{Feature, CURRENT_LOCATION},
+};
+
+const HISTORY_FILE: &str = "history.json";
+const HISTORY_LIMIT: usize = 20;
+const FAVORITES_FILE: &str = "favorites.json";
+const LAST_TRIP_FILE: &str = "last_trip.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Favorite {
+    pub name: String,
+    pub feature: Feature,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Trip {
+    pub from: Feature,
+    pub via: Vec<Feature>,
+    pub to: Feature,
+}
+
+pub fn route_name(from: &Feature, via: &[Feature], to: &Feature) -> String {
+    std::iter::once(from)
+        .chain(via)
+        .chain([to])
+        .map(|feature| feature.properties.label.as_str())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+impl Trip {
+    pub fn name(&self) -> String {
+        route_name(&self.from, &self.via, &self.to)
+    }
+
+    // Replaying these would reuse wherever the user was when they planned it
+    pub fn uses_current_location(&self) -> bool {
+        std::iter::once(&self.from)
+            .chain(&self.via)
+            .chain([&self.to])
+            .any(|feature| feature.properties.label == CURRENT_LOCATION)
+    }
+}
+
+fn load<T: DeserializeOwned>(name: &str) -> Result<T> {
+    let path =
+        config_dir().ok_or_else(|| anyhow!("Could not determine the configuration directory"))?;
+    Ok(serde_json::from_str(&fs::read_to_string(path.join(name))?)?)
+}
+
+fn save<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    let path =
+        config_dir().ok_or_else(|| anyhow!("Could not determine the configuration directory"))?;
+    fs::create_dir_all(&path)?;
+    fs::write(path.join(name), serde_json::to_string_pretty(value)?)?;
+    Ok(())
+}
+
+pub fn load_history() -> Vec<Feature> {
+    load(HISTORY_FILE).unwrap_or_default()
+}
+
+pub fn record_history(feature: &Feature) -> Result<()> {
+    let mut history = load_history();
+    history.retain(|entry| entry.properties.label != feature.properties.label);
+    history.insert(0, feature.clone());
+    history.truncate(HISTORY_LIMIT);
+    save(HISTORY_FILE, &history)
+}
+
+pub fn load_favorites() -> Vec<Favorite> {
+    load(FAVORITES_FILE).unwrap_or_default()
+}
+
+pub fn add_favorite(name: &str, feature: &Feature) -> Result<()> {
+    let mut favorites = load_favorites();
+    favorites.retain(|favorite| favorite.name != name);
+    favorites.push(Favorite { name: name.to_string(), feature: feature.clone() });
+    save(FAVORITES_FILE, &favorites)
+}
+
+pub fn load_last_trip() -> Option<Trip> {
+    load(LAST_TRIP_FILE).ok()
+}
+
+pub fn save_last_trip(trip: &Trip) -> Result<()> {
+    save(LAST_TRIP_FILE, trip)
+}
@@ -0,0 +1,96 @@
+use std::{collections::HashMap, env, fs, str::FromStr};
+
+use ratatui::style::Color;
+
+use crate::config::config_dir;
+
+const THEME_FILE: &str = "theme.toml";
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub walk: Color,
+    pub bicycle: Color,
+    pub bus: Color,
+    pub tram: Color,
+    pub rail: Color,
+    pub subway: Color,
+    pub ferry: Color,
+    pub other: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme {
+            walk: Color::Reset,
+            bicycle: Color::Yellow,
+            bus: Color::Blue,
+            tram: Color::Green,
+            rail: Color::Magenta,
+            subway: Color::Red,
+            ferry: Color::Cyan,
+            other: Color::Reset,
+        }
+    }
+}
+
+impl Theme {
+    pub fn hsl() -> Theme {
+        Theme {
+            walk: Color::Reset,
+            bicycle: Color::Rgb(0xfc, 0xb9, 0x19),
+            bus: Color::Rgb(0x00, 0x7a, 0xc9),
+            tram: Color::Rgb(0x00, 0x98, 0x5f),
+            rail: Color::Rgb(0x8c, 0x47, 0x99),
+            subway: Color::Rgb(0xff, 0x63, 0x19),
+            ferry: Color::Rgb(0x00, 0xb9, 0xe4),
+            other: Color::Reset,
+        }
+    }
+
+    fn set(&mut self, mode: &str, color: Color) -> bool {
+        let slot = match mode {
+            "walk" => &mut self.walk,
+            "bicycle" => &mut self.bicycle,
+            "bus" => &mut self.bus,
+            "tram" => &mut self.tram,
+            "rail" => &mut self.rail,
+            "subway" => &mut self.subway,
+            "ferry" => &mut self.ferry,
+            "other" => &mut self.other,
+            _ => return false,
+        };
+        *slot = color;
+        true
+    }
+
+    pub fn load() -> Theme {
+        let mut theme = match env::var("RSHSL_THEME").as_deref() {
+            Ok("hsl") => Theme::hsl(),
+            Ok("default") | Err(_) => Theme::default(),
+            Ok(name) => {
+                tracing::warn!("Ignoring unknown theme {:?}", name);
+                Theme::default()
+            }
+        };
+        let Some(path) = config_dir().map(|dir| dir.join(THEME_FILE)) else {
+            return theme;
+        };
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return theme;
+        };
+        let colors: HashMap<String, String> = match toml::from_str(&contents) {
+            Ok(colors) => colors,
+            Err(error) => {
+                tracing::warn!("Ignoring invalid theme {}: {}", path.display(), error);
+                return theme;
+            }
+        };
+        for (mode, color) in colors {
+            match Color::from_str(&color) {
+                Ok(color) if theme.set(&mode, color) => (),
+                _ => tracing::warn!("Ignoring theme color {:?} for {:?}", color, mode),
+            }
+        }
+        theme
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode};
+use ratatui::{
+    backend::Backend,
+    layout::{Alignment, Rect},
+    widgets::{Block, Borders, Paragraph, Wrap},
+    Frame, Terminal,
+};
+
+const MIN_WIDTH: u16 = 40;
+const MIN_HEIGHT: u16 = 12;
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const SPINNER_TICKS: usize = 6;
+
+pub fn too_small(frame: &mut Frame) -> bool {
+    let size = frame.size();
+    if size.width >= MIN_WIDTH && size.height >= MIN_HEIGHT {
+        return false;
+    }
+    let message = Paragraph::new(format!(
+        "Terminal too small ({}x{}), need at least {}x{}",
+        size.width, size.height, MIN_WIDTH, MIN_HEIGHT
+    ))
+    .alignment(Alignment::Center)
+    .wrap(Wrap { trim: true });
+    let area =
+        Rect::new(size.x, size.y + size.height / 2, size.width, size.height - size.height / 2);
+    frame.render_widget(message, area);
+    true
+}
+
+pub fn spinner(tick: usize) -> char {
+    SPINNER[tick / SPINNER_TICKS % SPINNER.len()]
+}
+
+pub fn format_distance(meters: f64) -> String {
+    if meters < 1000.0 {
+        format!("{:.0} m", meters)
+    } else {
+        format!("{:.1} km", meters / 1000.0)
+    }
+}
+
+pub fn vim_key(code: KeyCode) -> KeyCode {
+    match code {
+        KeyCode::Char('j') => KeyCode::Down,
+        KeyCode::Char('k') => KeyCode::Up,
+        KeyCode::Char('g') => KeyCode::Home,
+        KeyCode::Char('G') => KeyCode::End,
+        code => code,
+    }
+}
+
+pub fn confirm<B: Backend>(terminal: &mut Terminal<B>, question: &str) -> Result<bool> {
+    loop {
+        terminal.draw(|frame| {
+            if too_small(frame) {
+                return;
+            }
+            let size = frame.size();
+            let area = Rect::new(size.x, size.y + size.height / 2 - 2, size.width, 4);
+            let prompt = Paragraph::new(question)
+                .alignment(Alignment::Center)
+                .wrap(Wrap { trim: true })
+                .block(
+                    Block::default().title("y/Enter: yes, any other key: no").borders(Borders::ALL),
+                );
+            frame.render_widget(prompt, area);
+        })?;
+        match event::read()? {
+            Event::Key(key) => {
+                return Ok(matches!(key.code, KeyCode::Char('y') | KeyCode::Enter));
+            }
+            Event::Resize(..) => terminal.clear()?,
+            _ => (),
+        }
+    }
+}